@@ -0,0 +1,11 @@
+#!/bin/sh
+# host_tests をカーネルの .cargo/config の外から実行する
+#
+# cargo は作業ディレクトリから上に向かって設定を探すので、リポジトリの中で実行すると
+# カーネル用の build-std が有効になり、std をソースからビルドすることになる。
+# 引数はそのまま cargo test に渡す。
+set -e
+dir="$(cd "$(dirname "$0")" && pwd)"
+toolchain="$(cat "$dir/../rust-toolchain")"
+cd "${TMPDIR:-/tmp}"
+exec cargo "+$toolchain" test --manifest-path "$dir/Cargo.toml" "$@"
@@ -0,0 +1,25 @@
+//! カーネルの `allocator` モジュールのうち、ホストでもビルドできる部分
+
+#[path = "../../src/allocator/buddy.rs"]
+pub mod buddy;
+#[path = "../../src/allocator/bump.rs"]
+pub mod bump;
+#[path = "../../src/allocator/common.rs"]
+mod common;
+#[path = "../../src/allocator/fixed_size_block.rs"]
+pub mod fixed_size_block;
+#[path = "../../src/allocator/linked_list.rs"]
+pub mod linked_list;
+#[cfg(test)]
+#[path = "../../src/allocator/test_heap.rs"]
+mod test_heap;
+#[path = "../../src/allocator/tlsf.rs"]
+pub mod tlsf;
+
+use self::common::{align_up, realloc_by_copy};
+
+pub use self::common::{ExtendHeap, Locked};
+
+/// カーネル内のテストが使うテスト用ヒープのサイズ
+#[cfg(test)]
+pub const HEAP_SIZE: usize = 100 * 1024;
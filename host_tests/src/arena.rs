@@ -0,0 +1,41 @@
+/// `Vec<u8>` の上に確保したテスト用のヒープ領域
+pub struct Arena {
+    // start 以降のメモリを所有するためだけに保持している
+    _buffer: Vec<u8>,
+    start: usize,
+    size: usize,
+}
+
+impl Arena {
+    /// align にアラインされた size バイトの領域を確保する
+    pub fn new(size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two());
+
+        let mut buffer = vec![0; size + align];
+        let addr = buffer.as_mut_ptr() as usize;
+        let start = (addr + align - 1) & !(align - 1);
+
+        Arena {
+            _buffer: buffer,
+            start,
+            size,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+
+    /// [addr, addr + size) がこの領域に収まっているかを返す
+    pub fn contains(&self, addr: usize, size: usize) -> bool {
+        addr >= self.start && addr + size <= self.end()
+    }
+}
@@ -0,0 +1,38 @@
+//! カーネルのアロケータをホスト上でテストするためのクレート
+//!
+//! `src/allocator` 以下のうちカーネルに依存しない実装を `#[path]` でそのまま読み込み、
+//! `Vec<u8>` で確保した領域をヒープとして使う。
+//! カーネル内の `#[test_case]` のテストも、このクレートの単体テストとして実行される。
+
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+
+pub mod allocator;
+pub mod arena;
+#[path = "../../tests/support/xorshift.rs"]
+pub mod xorshift;
+
+#[cfg(test)]
+trait Testable {
+    fn run(&self);
+}
+
+#[cfg(test)]
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        print!("{}...\t", core::any::type_name::<T>());
+        self();
+        println!("[ok]");
+    }
+}
+
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Testable]) {
+    println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    collections::BTreeMap,
+    ptr, slice,
+};
+
+use blog_os_host_tests::{
+    allocator::{
+        buddy::BuddyAllocator,
+        bump::BumpAllocator,
+        fixed_size_block::FixedSizeBlockAllocator,
+        linked_list::{FitStrategy, LinkedListAllocator},
+        tlsf::TlsfAllocator,
+        Locked,
+    },
+    arena::Arena,
+    xorshift::XorShift,
+};
+
+const ARENA_SIZE: usize = 1024 * 1024;
+const OPERATIONS: usize = 20_000;
+const SEEDS: &[u64] = &[1, 0xDEAD_BEEF, 0x1234_5678_9ABC_DEF0];
+
+/// 割り当て中のブロック
+struct Block {
+    layout: Layout,
+    /// ブロック全体を埋めているバイト
+    fill: u8,
+}
+
+/// 割り当て中のブロックを開始アドレス順に管理し、結果の正しさを検査する
+struct Tracker<'a> {
+    arena: &'a Arena,
+    blocks: BTreeMap<usize, Block>,
+    next_fill: u8,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(arena: &'a Arena) -> Self {
+        Tracker {
+            arena,
+            blocks: BTreeMap::new(),
+            next_fill: 0,
+        }
+    }
+
+    /// 新しく割り当てられたブロックを検査して登録し、固有のバイトで埋める
+    fn insert(&mut self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        assert_eq!(
+            addr % layout.align(),
+            0,
+            "{:?} at {:#x} is misaligned",
+            layout,
+            addr
+        );
+        assert!(
+            self.arena.contains(addr, layout.size()),
+            "{:?} at {:#x} is outside of the arena",
+            layout,
+            addr
+        );
+
+        if let Some((&prev, block)) = self.blocks.range(..=addr).next_back() {
+            assert!(
+                prev + block.layout.size() <= addr,
+                "{:?} at {:#x} overlaps {:?} at {:#x}",
+                layout,
+                addr,
+                block.layout,
+                prev
+            );
+        }
+        if let Some((&next, block)) = self.blocks.range(addr..).next() {
+            assert!(
+                addr + layout.size() <= next,
+                "{:?} at {:#x} overlaps {:?} at {:#x}",
+                layout,
+                addr,
+                block.layout,
+                next
+            );
+        }
+
+        self.next_fill = self.next_fill.wrapping_add(1);
+        let fill = self.next_fill;
+        unsafe { ptr::write_bytes(ptr, fill, layout.size()) };
+        self.blocks.insert(addr, Block { layout, fill });
+    }
+
+    /// ブロックを登録から外す。書き込んだ内容が壊されていればパニックする
+    fn remove(&mut self, addr: usize) -> Block {
+        let block = self.blocks.remove(&addr).unwrap();
+        Self::check_contents(addr, block.layout.size(), block.fill);
+        block
+    }
+
+    fn check_contents(addr: usize, len: usize, fill: u8) {
+        let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+        if let Some(offset) = bytes.iter().position(|&b| b != fill) {
+            panic!("byte at {:#x} was overwritten", addr + offset);
+        }
+    }
+
+    /// 割り当て中のブロックを一つ選ぶ
+    fn pick(&self, rng: &mut XorShift) -> Option<usize> {
+        if self.blocks.is_empty() {
+            return None;
+        }
+        self.blocks
+            .keys()
+            .nth(rng.below(self.blocks.len()))
+            .copied()
+    }
+}
+
+/// 小さなサイズを中心に、ときどき大きなサイズや大きなアラインメントを混ぜたレイアウトを返す
+fn random_layout(rng: &mut XorShift) -> Layout {
+    let size = match rng.below(16) {
+        0 => 1 + rng.below(16 * 1024),
+        1..=3 => 1 + rng.below(4096),
+        _ => 1 + rng.below(256),
+    };
+    let align = match rng.below(32) {
+        0 => 4096,
+        _ => 1 << rng.below(7),
+    };
+    Layout::from_size_align(size, align).unwrap()
+}
+
+/// 割り当て・解放・realloc をランダムに繰り返し、最後にすべて解放する
+///
+/// allow_oom が false のときは、割り当ての失敗もエラーとして扱う。
+fn run_random<A: GlobalAlloc>(allocator: &A, arena: &Arena, seed: u64, allow_oom: bool) {
+    let mut rng = XorShift::new(seed);
+    let mut tracker = Tracker::new(arena);
+    let mut allocated = 0;
+
+    for _ in 0..OPERATIONS {
+        match rng.below(10) {
+            // 解放
+            0..=3 => {
+                if let Some(addr) = tracker.pick(&mut rng) {
+                    let block = tracker.remove(addr);
+                    unsafe { allocator.dealloc(addr as *mut u8, block.layout) };
+                }
+            }
+            // realloc
+            4..=5 => {
+                if let Some(addr) = tracker.pick(&mut rng) {
+                    let old = tracker.remove(addr);
+                    let new_size = random_layout(&mut rng).size();
+                    let ptr = unsafe { allocator.realloc(addr as *mut u8, old.layout, new_size) };
+
+                    if ptr.is_null() {
+                        assert!(allow_oom, "realloc to {} bytes failed", new_size);
+                        // 失敗したときは元のブロックが残っている
+                        Tracker::check_contents(addr, old.layout.size(), old.fill);
+                        tracker.blocks.insert(addr, old);
+                        continue;
+                    }
+
+                    // 元の内容は新しいサイズに収まる分だけ引き継がれる
+                    let kept = old.layout.size().min(new_size);
+                    Tracker::check_contents(ptr as usize, kept, old.fill);
+                    let layout = Layout::from_size_align(new_size, old.layout.align()).unwrap();
+                    tracker.insert(ptr, layout);
+                }
+            }
+            // 割り当て
+            _ => {
+                let layout = random_layout(&mut rng);
+                let ptr = unsafe { allocator.alloc(layout) };
+                if ptr.is_null() {
+                    assert!(allow_oom, "allocation of {:?} failed", layout);
+                    continue;
+                }
+                tracker.insert(ptr, layout);
+                allocated += 1;
+            }
+        }
+    }
+
+    assert!(allocated > 0);
+
+    let addrs: Vec<usize> = tracker.blocks.keys().copied().collect();
+    for addr in addrs {
+        let block = tracker.remove(addr);
+        unsafe { allocator.dealloc(addr as *mut u8, block.layout) };
+    }
+}
+
+/// 領域全体を一度に割り当てられることを確認する
+fn assert_whole_arena_available<A: GlobalAlloc>(allocator: &A, arena: &Arena) {
+    let layout = Layout::from_size_align(arena.size(), 8).unwrap();
+    let ptr = unsafe { allocator.alloc(layout) };
+    assert_eq!(ptr as usize, arena.start());
+    unsafe { allocator.dealloc(ptr, layout) };
+}
+
+#[test]
+fn bump_random() {
+    for &seed in SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 4096);
+        let allocator = Locked::new(BumpAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        // バンプアロケータは領域を再利用しないので、途中で使い切ることがある
+        run_random(&allocator, &arena, seed, true);
+
+        // すべて解放されると先頭に戻る
+        assert_whole_arena_available(&allocator, &arena);
+    }
+}
+
+fn linked_list_random(strategy: FitStrategy) {
+    for &seed in SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 4096);
+        let allocator = Locked::new(LinkedListAllocator::with_strategy(strategy));
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        run_random(&allocator, &arena, seed, false);
+
+        assert_eq!(allocator.lock().largest_free_region(), arena.size());
+        assert_whole_arena_available(&allocator, &arena);
+    }
+}
+
+#[test]
+fn linked_list_first_fit_random() {
+    linked_list_random(FitStrategy::FirstFit);
+}
+
+#[test]
+fn linked_list_best_fit_random() {
+    linked_list_random(FitStrategy::BestFit);
+}
+
+#[test]
+fn fixed_size_block_random() {
+    for &seed in SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 4096);
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+        run_random(&allocator, &arena, seed, false);
+
+        let stats = allocator.lock().stats();
+        assert!(stats.size_classes.iter().all(|class| class.allocated == 0));
+
+        // フリーリストのブロックを返却すれば、フォールバックアロケータは空になる
+        allocator.lock().reclaim();
+        assert_eq!(allocator.lock().stats().fallback_used, 0);
+    }
+}
+
+#[test]
+fn buddy_random() {
+    for &seed in SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 4096);
+        let allocator = Locked::new(BuddyAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+        let free_before = allocator.lock().free_bytes();
+
+        // 2 のべき乗に切り上げるぶん容量を食うので、途中で使い切ることがある
+        run_random(&allocator, &arena, seed, true);
+
+        // すべてのブロックがバディと結合されて元に戻る
+        assert_eq!(allocator.lock().free_bytes(), free_before);
+    }
+}
+
+#[test]
+fn tlsf_random() {
+    for &seed in SEEDS {
+        let arena = Arena::new(ARENA_SIZE, 4096);
+        let allocator = Locked::new(TlsfAllocator::new());
+        unsafe { allocator.lock().init(arena.start(), arena.size()) };
+        let free_before = allocator.lock().free_bytes();
+
+        run_random(&allocator, &arena, seed, false);
+
+        // 隣接する空きブロックが結合されて一つに戻る
+        assert_eq!(allocator.lock().free_bytes(), free_before);
+    }
+}
+
+#[test]
+fn small_blocks_do_not_overlap() {
+    let arena = Arena::new(ARENA_SIZE, 4096);
+    let allocator = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe { allocator.lock().init(arena.start(), arena.size()) };
+
+    // すべてのサイズクラスとアラインメントの組み合わせを同時に割り当てておく
+    let mut tracker = Tracker::new(&arena);
+    for size in (1..=2048).step_by(7) {
+        let align = 1 << (size % 12);
+        let layout = Layout::from_size_align(size, align).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        tracker.insert(ptr, layout);
+    }
+
+    let addrs: Vec<usize> = tracker.blocks.keys().copied().collect();
+    for addr in addrs {
+        let block = tracker.remove(addr);
+        unsafe { allocator.dealloc(addr as *mut u8, block.layout) };
+    }
+}
@@ -1,6 +1,19 @@
+pub mod arena;
+pub mod buddy;
 pub mod bump;
+mod common;
+#[cfg(feature = "debug-heap")]
+pub mod debug;
 pub mod fixed_size_block;
+pub mod growable;
 pub mod linked_list;
+pub mod oom;
+pub mod slab;
+pub mod stats;
+#[cfg(test)]
+mod test_heap;
+pub mod tlsf;
+pub mod trace;
 
 use x86_64::{
     structures::paging::{
@@ -9,40 +22,90 @@ use x86_64::{
     VirtAddr,
 };
 
-use self::fixed_size_block::FixedSizeBlockAllocator;
+use self::{
+    common::{align_up, realloc_by_copy},
+    growable::GrowableHeap,
+    stats::{HeapStats, StatsAlloc},
+    trace::TraceAlloc,
+};
 
-// Trait 実装用のラッパー
-pub struct Locked<A> {
-    inner: spin::Mutex<A>,
-}
+pub use self::common::{ExtendHeap, Locked};
 
-impl<A> Locked<A> {
-    pub const fn new(inner: A) -> Self {
-        Locked {
-            inner: spin::Mutex::new(inner),
-        }
-    }
+/// 適当な仮想アドレス。拡張した部分に 2MiB のページを使えるよう、2MiB 境界に揃えておく
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+/// 起動時にマップするヒープのサイズ
+pub const HEAP_SIZE: usize = 100 * 1024;
+/// ヒープ用に予約する仮想アドレス範囲のサイズ
+/// ヒープはこの範囲内で必要に応じて拡張される
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// vma に記録するヒープの範囲の持ち主
+pub const HEAP_OWNER: &str = "heap";
+/// ヒープのページをマップするときのフラグ
+const HEAP_PAGE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
-        self.inner.lock()
-    }
-}
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-buddy"),
+    all(feature = "alloc-bump", feature = "alloc-tlsf"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-buddy"),
+    all(feature = "alloc-linked-list", feature = "alloc-tlsf"),
+    all(feature = "alloc-fixed-block", feature = "alloc-buddy"),
+    all(feature = "alloc-fixed-block", feature = "alloc-tlsf"),
+    all(feature = "alloc-buddy", feature = "alloc-tlsf"),
+))]
+compile_error!(
+    "features `alloc-bump`, `alloc-linked-list`, `alloc-fixed-block`, `alloc-buddy` and `alloc-tlsf` are mutually exclusive"
+);
 
-pub const HEAP_START: usize = 0x_4444_4444_0000; // 適当な仮想アドレス
-pub const HEAP_SIZE: usize = 100 * 1024;
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-buddy",
+    feature = "alloc-tlsf",
+)))]
+compile_error!(
+    "one of `alloc-bump`, `alloc-linked-list`, `alloc-fixed-block`, `alloc-buddy` or `alloc-tlsf` must be enabled"
+);
 
-fn align_up(addr: usize, align: usize) -> usize {
-    // align - 1 は align を満たす bit よりも下位がすべて 1 である数値
-    // つまり、!(align - 1) は align を満たす bit より上位がすべて 1 である数値
-    //
-    // addr & !(align - 1) で、addr 以下で最も近いアラインメントを満たす下向きのアラインができる
-    // 予め対象を (addr + (align - 1)) と加算しておくことで、1アラインメント分ズラした上で計算する
-    // -> 上向きのアラインを得る
-    (addr + align - 1) & !(align - 1)
-}
+// cargo の feature で選択されたグローバルアロケータ
+#[cfg(feature = "alloc-bump")]
+type GlobalAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+type GlobalAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed-block")]
+type GlobalAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-buddy")]
+type GlobalAllocator = buddy::BuddyAllocator;
+#[cfg(feature = "alloc-tlsf")]
+type GlobalAllocator = tlsf::TlsfAllocator;
+
+#[global_allocator]
+#[cfg(not(feature = "debug-heap"))]
+static ALLOCATOR: StatsAlloc<TraceAlloc<GrowableHeap<GlobalAllocator>>> =
+    StatsAlloc::new(TraceAlloc::new(GrowableHeap::new(GlobalAllocator::new())));
 
+// debug-heap feature が有効なときは、ガード領域とポイズンで割り当てを検査する
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+#[cfg(feature = "debug-heap")]
+static ALLOCATOR: StatsAlloc<TraceAlloc<debug::DebugHeap<GrowableHeap<GlobalAllocator>>>> =
+    StatsAlloc::new(TraceAlloc::new(debug::DebugHeap::new(GrowableHeap::new(
+        GlobalAllocator::new(),
+    ))));
+
+/// ラッパーを除いたヒープ本体を返す
+fn heap() -> &'static GrowableHeap<GlobalAllocator> {
+    #[cfg(feature = "debug-heap")]
+    let heap = ALLOCATOR.inner().inner().inner();
+    #[cfg(not(feature = "debug-heap"))]
+    let heap = ALLOCATOR.inner().inner();
+
+    heap
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -59,20 +122,95 @@ pub fn init_heap(
 
     // 作成したページ範囲内にフレームをマッピングする
     for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-
-        // NOTE: flush() することで TLB を明示的に更新する
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        map_heap_page(page, mapper, frame_allocator)?;
     }
 
     // アロケータを指定した仮想アドレス範囲で初期化する
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        heap().lock().init(HEAP_START, HEAP_SIZE);
     }
+    heap().set_mapped_range(HEAP_START, HEAP_SIZE);
+
+    // 拡張できる範囲全体を、他の用途に使われないよう記録しておく
+    crate::memory::vma::reserve_fixed(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_MAX_SIZE as u64,
+        HEAP_PAGE_FLAGS,
+        HEAP_OWNER,
+    )
+    .expect("heap range already in use");
+
+    Ok(())
+}
+
+/// ヒープを拡張できる上限のサイズを設定する。
+///
+/// HEAP_MAX_SIZE より大きな値を与えた場合は HEAP_MAX_SIZE に切り詰められる。
+pub fn set_heap_limit(limit: usize) {
+    heap().set_limit(limit);
+}
+
+/// 現在マップされているヒープのサイズを返す
+pub fn heap_size() -> usize {
+    heap().size()
+}
+
+/// 割り当て中のバイト数を返す
+pub fn live_bytes() -> usize {
+    ALLOCATOR.live_bytes()
+}
+
+/// グローバルアロケータの使用状況を返す
+pub fn stats() -> HeapStats {
+    #[cfg(feature = "alloc-fixed-block")]
+    let fixed_size_block = Some(heap().lock().stats());
+    #[cfg(not(feature = "alloc-fixed-block"))]
+    let fixed_size_block = None;
+
+    HeapStats {
+        live_bytes: ALLOCATOR.live_bytes(),
+        peak_bytes: ALLOCATOR.peak_bytes(),
+        allocations: ALLOCATOR.allocations(),
+        frees: ALLOCATOR.frees(),
+        reallocations: ALLOCATOR.reallocations(),
+        in_place_reallocations: ALLOCATOR.in_place_reallocations(),
+        heap_size: heap_size(),
+        blocked_grows: heap().blocked_grows(),
+        fixed_size_block,
+    }
+}
+
+/// f の実行前後で割り当て中のバイト数が変わらないことを確認する
+///
+/// f の中で割り当てたメモリが解放されずに残っていればパニックする。
+pub fn assert_no_leaks<R>(f: impl FnOnce() -> R) -> R {
+    let live_before = ALLOCATOR.live_bytes();
+    let result = f();
+    let live_after = ALLOCATOR.live_bytes();
+
+    assert!(
+        live_after <= live_before,
+        "{} bytes leaked",
+        live_after - live_before
+    );
+
+    result
+}
+
+/// ヒープ用のページにフレームを割り当ててマップする
+fn map_heap_page(
+    page: Page<Size4KiB>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+
+    let flags = HEAP_PAGE_FLAGS;
+
+    // NOTE: flush() することで TLB を明示的に更新する
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
 
     Ok(())
 }
@@ -0,0 +1,163 @@
+use core::{
+    alloc::{AllocError, Allocator, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use alloc::alloc::{alloc, dealloc};
+
+use super::{align_up, bump::BumpAllocator, Locked};
+
+/// カーネルヒープから一度に確保するチャンクの最小サイズ
+const CHUNK_SIZE: usize = 16 * 1024;
+const PAGE_SIZE: usize = 4096;
+
+/// 各チャンクの先頭に置き、確保済みのチャンクを連結する
+struct ChunkHeader {
+    next: *mut ChunkHeader,
+    size: usize,
+}
+
+struct ArenaInner {
+    /// 現在のチャンクから切り出すバンプアロケータ
+    bump: BumpAllocator,
+    /// 最後に確保したチャンク
+    chunks: *mut ChunkHeader,
+    /// 確保したチャンクの合計サイズ
+    chunk_bytes: usize,
+}
+
+unsafe impl Send for ArenaInner {}
+
+/// 短命な割り当てをまとめて扱うアリーナ
+///
+/// カーネルヒープから確保したチャンクをバンプアロケータで切り出す。個々の解放では何もせず、
+/// アリーナを drop したときにすべてのチャンクを一度に返す。
+/// `Vec::new_in(&arena)` や `Box::new_in(value, &arena)` のように使う。
+pub struct Arena {
+    inner: Locked<ArenaInner>,
+}
+
+impl Arena {
+    pub const fn new() -> Self {
+        Arena {
+            inner: Locked::new(ArenaInner {
+                bump: BumpAllocator::new(),
+                chunks: ptr::null_mut(),
+                chunk_bytes: 0,
+            }),
+        }
+    }
+
+    /// カーネルヒープから確保しているバイト数を返す
+    pub fn chunk_bytes(&self) -> usize {
+        self.inner.lock().chunk_bytes
+    }
+}
+
+impl ArenaInner {
+    /// layout が収まる新しいチャンクを確保して、バンプアロケータの対象を切り替える
+    fn add_chunk(&mut self, layout: Layout) -> Result<(), AllocError> {
+        let header_size = mem::size_of::<ChunkHeader>();
+        let required = header_size
+            .checked_add(layout.size())
+            .and_then(|size| size.checked_add(layout.align()))
+            .ok_or(AllocError)?;
+        let size = align_up(required.max(CHUNK_SIZE), PAGE_SIZE);
+        let chunk_layout = Layout::from_size_align(size, PAGE_SIZE).map_err(|_| AllocError)?;
+
+        let chunk = unsafe { alloc(chunk_layout) } as *mut ChunkHeader;
+        if chunk.is_null() {
+            return Err(AllocError);
+        }
+
+        unsafe {
+            chunk.write(ChunkHeader {
+                next: self.chunks,
+                size,
+            });
+            // 前のチャンクの残りは捨てる
+            self.bump
+                .set_region(chunk as usize + header_size, size - header_size);
+        }
+        self.chunks = chunk;
+        self.chunk_bytes += size;
+        Ok(())
+    }
+}
+
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let mut inner = self.inner.lock();
+
+        let mut ptr = inner.bump.bump(layout);
+        if ptr.is_null() {
+            inner.add_chunk(layout)?;
+            ptr = inner.bump.bump(layout);
+        }
+
+        let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {
+        // drop でまとめて解放する
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let inner = self.inner.lock();
+
+        let mut chunk = inner.chunks;
+        while !chunk.is_null() {
+            unsafe {
+                let ChunkHeader { next, size } = chunk.read();
+                dealloc(
+                    chunk as *mut u8,
+                    Layout::from_size_align_unchecked(size, PAGE_SIZE),
+                );
+                chunk = next;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator;
+    use alloc::{boxed::Box, vec::Vec};
+
+    #[test_case]
+    fn vec_and_box_in_arena() {
+        allocator::assert_no_leaks(|| {
+            let arena = Arena::new();
+            let allocations_before = allocator::stats().allocations;
+
+            let mut values = Vec::new_in(&arena);
+            for i in 0..1000 {
+                values.push(i);
+            }
+            let boxed = Box::new_in([7u8; 100], &arena);
+
+            assert_eq!(values.iter().sum::<u64>(), (0..1000).sum());
+            assert_eq!(boxed[99], 7);
+            // グローバルヒープへの割り当てはチャンクの分だけ
+            let chunks = allocator::stats().allocations - allocations_before;
+            assert!(chunks > 0 && chunks * CHUNK_SIZE <= arena.chunk_bytes());
+        });
+    }
+
+    #[test_case]
+    fn large_allocation_gets_own_chunk() {
+        let arena = Arena::new();
+        let mut large = Vec::with_capacity_in(CHUNK_SIZE * 2, &arena);
+        large.resize(CHUNK_SIZE * 2, 1u8);
+        assert!(arena.chunk_bytes() > CHUNK_SIZE * 2);
+        assert_eq!(
+            large.iter().map(|&b| b as usize).sum::<usize>(),
+            CHUNK_SIZE * 2
+        );
+    }
+}
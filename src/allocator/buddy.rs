@@ -0,0 +1,457 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use super::{align_up, realloc_by_copy, ExtendHeap, Locked};
+
+/// 最小のブロックサイズ。空きブロックのヘッダが収まる 2^N でないといけない
+const MIN_BLOCK_SIZE: usize = 32;
+/// 扱うブロックサイズの段数。最大のブロックは MIN_BLOCK_SIZE << (ORDERS - 1)
+const ORDERS: usize = 32;
+
+/// 空きブロックの先頭に書き込まれるヘッダ
+struct FreeBlock {
+    prev: Option<NonNull<FreeBlock>>,
+    next: Option<NonNull<FreeBlock>>,
+    order: usize,
+}
+
+/// バディシステムによるヒープアロケータ
+///
+/// ブロックは MIN_BLOCK_SIZE << order の大きさで、そのサイズに自然にアラインされている。
+/// 解放したブロックは、相方 (バディ) が空いていれば必ず結合される。
+/// どのアドレスから空きブロックが始まるかをビットマップで管理しているので、
+/// 割り当てと解放はどちらも O(log n) で終わる。
+pub struct BuddyAllocator {
+    free_lists: [Option<NonNull<FreeBlock>>; ORDERS],
+    heap_start: usize,
+    heap_end: usize,
+    /// ブロックとして管理している領域の終端
+    ///
+    /// ここから heap_end までは、ビットマップの容量が足りずにまだ管理できていない領域。
+    managed_end: usize,
+    /// 空きブロックの先頭を MIN_BLOCK_SIZE ごとに 1 ビットで示すビットマップ
+    ///
+    /// ビットマップ自身もヒープ内に置かれる。
+    bitmap: *mut u64,
+    bitmap_words: usize,
+    free_bytes: usize,
+}
+
+// NOTE: ブロックはこのアロケータのロック下でのみ操作する
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            free_lists: [None; ORDERS],
+            heap_start: 0,
+            heap_end: 0,
+            managed_end: 0,
+            bitmap: ptr::null_mut(),
+            bitmap_words: 0,
+            free_bytes: 0,
+        }
+    }
+
+    /// 与えられたヒープ境界でアロケータを初期化する。
+    ///
+    /// 呼び出し元はヒープ領域が未使用であることを保証しなければならない。
+    /// また、このメソッドは一度しか呼ばれてはならない。
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = align_up(heap_start, MIN_BLOCK_SIZE);
+        self.heap_end = heap_start + heap_size;
+        self.managed_end = self.heap_start;
+        self.manage_pending();
+    }
+
+    /// 空きブロックの合計バイト数を返す
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// 最も大きい空きブロックのサイズを返す
+    pub fn largest_free_block(&self) -> usize {
+        (0..ORDERS)
+            .rev()
+            .find(|&order| self.free_lists[order].is_some())
+            .map_or(0, block_size)
+    }
+
+    /// order のブロックを割り当てる。空きがなければ None を返す
+    fn allocate(&mut self, order: usize) -> Option<usize> {
+        // order 以上で空きブロックのある最小の段を探し、必要な大きさまで分割する
+        let mut current = (order..ORDERS).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(current)?;
+
+        while current > order {
+            current -= 1;
+            unsafe { self.push(addr + block_size(current), current) };
+        }
+
+        Some(addr)
+    }
+
+    /// order のブロックを解放し、空いているバディと可能な限り結合する
+    unsafe fn deallocate(&mut self, mut addr: usize, mut order: usize) {
+        while order + 1 < ORDERS {
+            let buddy = addr ^ block_size(order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+
+            self.remove(NonNull::new_unchecked(buddy as *mut FreeBlock));
+            addr = addr.min(buddy);
+            order += 1;
+        }
+
+        self.push(addr, order);
+    }
+
+    /// addr から始まる order のブロックを、後ろに続く空きバディを取り込んで new_order まで広げる
+    ///
+    /// 途中のバディがすべて丸ごと空いているときだけ広げ、広げられなければ何もせずに false を返す。
+    fn grow_in_place(&mut self, addr: usize, order: usize, new_order: usize) -> bool {
+        // 広げたブロックも自然にアラインされていなければならない
+        if addr % block_size(new_order) != 0 {
+            return false;
+        }
+        if !(order..new_order).all(|o| self.is_free_block(addr + block_size(o), o)) {
+            return false;
+        }
+
+        for o in order..new_order {
+            unsafe {
+                self.remove(NonNull::new_unchecked(
+                    (addr + block_size(o)) as *mut FreeBlock,
+                ))
+            };
+        }
+        true
+    }
+
+    /// [start, end) を自然にアラインされた最大のブロックに区切って解放する
+    unsafe fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let order = (0..ORDERS)
+                .rev()
+                .find(|&order| start % block_size(order) == 0 && start + block_size(order) <= end)
+                .expect("range is not aligned to MIN_BLOCK_SIZE");
+            self.deallocate(start, order);
+            start += block_size(order);
+        }
+    }
+
+    /// addr から始まる order の空きブロックがあるかを返す
+    fn is_free_block(&self, addr: usize, order: usize) -> bool {
+        if addr < self.heap_start || addr + block_size(order) > self.managed_end {
+            return false;
+        }
+
+        // ビットが立っているアドレスにだけ FreeBlock が書き込まれている
+        self.test_bit(addr) && unsafe { (*(addr as *const FreeBlock)).order == order }
+    }
+
+    /// ビットマップで管理できる領域の終端
+    fn bitmap_end(&self) -> usize {
+        self.heap_start + self.bitmap_words * 64 * MIN_BLOCK_SIZE
+    }
+
+    fn bit_position(&self, addr: usize) -> (usize, u64) {
+        let index = (addr - self.heap_start) / MIN_BLOCK_SIZE;
+        (index / 64, 1 << (index % 64))
+    }
+
+    fn test_bit(&self, addr: usize) -> bool {
+        let (word, mask) = self.bit_position(addr);
+        unsafe { *self.bitmap.add(word) & mask != 0 }
+    }
+
+    fn set_bit(&mut self, addr: usize, value: bool) {
+        let (word, mask) = self.bit_position(addr);
+        unsafe {
+            let word = &mut *self.bitmap.add(word);
+            if value {
+                *word |= mask;
+            } else {
+                *word &= !mask;
+            }
+        }
+    }
+
+    /// addr から始まる order のブロックを空きリストの先頭に追加する
+    unsafe fn push(&mut self, addr: usize, order: usize) {
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock {
+            prev: None,
+            next: self.free_lists[order],
+            order,
+        });
+        if let Some(mut next) = self.free_lists[order] {
+            next.as_mut().prev = NonNull::new(block);
+        }
+        self.free_lists[order] = NonNull::new(block);
+
+        self.set_bit(addr, true);
+        self.free_bytes += block_size(order);
+    }
+
+    /// order の空きリストの先頭のブロックを取り出す
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order]?;
+        unsafe { self.remove(block) };
+        Some(block.as_ptr() as usize)
+    }
+
+    /// 空きリストからブロックを取り除く
+    unsafe fn remove(&mut self, block: NonNull<FreeBlock>) {
+        let FreeBlock { prev, next, order } = block.as_ptr().read();
+        match prev {
+            Some(mut prev) => prev.as_mut().next = next,
+            None => self.free_lists[order] = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().prev = prev;
+        }
+
+        self.set_bit(block.as_ptr() as usize, false);
+        self.free_bytes -= block_size(order);
+    }
+
+    /// managed_end から heap_end までの未管理の領域をブロックとして追加する
+    ///
+    /// ビットマップの容量が足りなければ、より大きなビットマップに移し替える。
+    unsafe fn manage_pending(&mut self) {
+        let end = self.heap_end & !(MIN_BLOCK_SIZE - 1);
+        if end <= self.managed_end {
+            return;
+        }
+
+        if self.bitmap_end() < end {
+            self.grow_bitmap(end);
+        }
+
+        let start = self.managed_end;
+        self.managed_end = end.min(self.bitmap_end());
+        if start < self.managed_end {
+            self.free_range(start, self.managed_end);
+        }
+    }
+
+    /// end までを管理できるビットマップに移し替える
+    ///
+    /// 何度も移し替えずに済むよう、必要な容量の 2 倍を確保する。
+    /// 置き場所が見つからなければ何もしない。
+    unsafe fn grow_bitmap(&mut self, end: usize) {
+        let bits = (end - self.heap_start) / MIN_BLOCK_SIZE * 2;
+        let words = (bits + 63) / 64;
+        let order = order_for_size(words * mem::size_of::<u64>()).expect("heap is too large");
+
+        // 既存の空きブロックから確保し、無理なら未管理の領域の先頭を使う
+        let new_bitmap = match self.allocate(order) {
+            Some(addr) => addr,
+            None if self.managed_end + block_size(order) <= end => {
+                let addr = self.managed_end;
+                self.managed_end += block_size(order);
+                addr
+            }
+            None => return,
+        } as *mut u64;
+        let new_words = block_size(order) / mem::size_of::<u64>();
+
+        ptr::write_bytes(new_bitmap, 0, new_words);
+        let old_bitmap = self.bitmap;
+        let old_words = self.bitmap_words;
+        self.bitmap = new_bitmap;
+        self.bitmap_words = new_words;
+
+        // 古いビットマップの内容を引き継ぎ、その領域を空きブロックに戻す
+        if old_words > 0 {
+            ptr::copy_nonoverlapping(old_bitmap, new_bitmap, old_words);
+            let start = old_bitmap as usize;
+            self.free_range(start, start + old_words * mem::size_of::<u64>());
+        }
+    }
+}
+
+impl ExtendHeap for BuddyAllocator {
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+        self.manage_pending();
+    }
+}
+
+fn block_size(order: usize) -> usize {
+    MIN_BLOCK_SIZE << order
+}
+
+/// size バイトを格納できる最小のブロックの order を返す
+fn order_for_size(size: usize) -> Option<usize> {
+    let block_size = size.max(MIN_BLOCK_SIZE).checked_next_power_of_two()?;
+    let order = (block_size / MIN_BLOCK_SIZE).trailing_zeros() as usize;
+    if order < ORDERS {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+/// ブロックは自身のサイズにアラインされるので、サイズとアラインメントの大きい方で order を決める
+fn order_for(layout: &Layout) -> Option<usize> {
+    order_for_size(layout.size().max(layout.align()))
+}
+
+unsafe impl GlobalAlloc for Locked<BuddyAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let order = match order_for(&layout) {
+            Some(order) => order,
+            None => return ptr::null_mut(),
+        };
+
+        match self.lock().allocate(order) {
+            Some(addr) => addr as *mut u8,
+            None => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let order = order_for(&layout).unwrap();
+        self.lock().deallocate(ptr as usize, order);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let old_order = order_for(&layout).unwrap();
+
+        match order_for(&new_layout) {
+            // 同じ大きさのブロックにそのまま収まる
+            Some(new_order) if new_order == old_order => ptr,
+            // 縮小するときは、後ろ半分を順に解放していく
+            Some(new_order) if new_order < old_order => {
+                let mut allocator = self.lock();
+                for order in new_order..old_order {
+                    allocator.deallocate(ptr as usize + block_size(order), order);
+                }
+                ptr
+            }
+            // 拡大するときは、後ろのバディが空いていればそのまま取り込む
+            Some(new_order)
+                if self
+                    .lock()
+                    .grow_in_place(ptr as usize, old_order, new_order) =>
+            {
+                ptr
+            }
+            _ => realloc_by_copy(self, ptr, layout, new_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::test_heap::{TestHeap, TEST_HEAP_CAPACITY};
+
+    /// 拡張できるよう領域全体を借り、先頭の heap_size バイトで初期化する
+    fn fresh_allocator(heap_size: usize) -> (TestHeap, Locked<BuddyAllocator>) {
+        let heap = TestHeap::take(TEST_HEAP_CAPACITY);
+        let allocator = Locked::new(BuddyAllocator::new());
+        unsafe { allocator.lock().init(heap.start(), heap_size) };
+        (heap, allocator)
+    }
+
+    /// 8 バイトから 64 KiB までのサイズを混ぜて割り当て、すべて解放すると元の状態に戻ることを確認する
+    #[test_case]
+    fn mixed_sizes_merge_back() {
+        let (_heap, allocator) = fresh_allocator(TEST_HEAP_CAPACITY);
+        let free_before = allocator.lock().free_bytes();
+        let largest_before = allocator.lock().largest_free_block();
+
+        let mut blocks = [(ptr::null_mut(), Layout::new::<u8>()); 96];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            // 8, 16, ..., 64 KiB と、その間の中途半端なサイズを交互に使う
+            let size = (8 << (i % 14)) - (i % 3) * 3;
+            let layout = Layout::from_size_align(size, 8 << (i % 4)).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            if ptr.is_null() {
+                continue;
+            }
+            assert_eq!(ptr as usize % layout.align(), 0);
+            unsafe { ptr::write_bytes(ptr, i as u8, size) };
+            *block = (ptr, layout);
+        }
+
+        // 奇数番目、偶数番目の順に解放して、結合の順序を入れ替える
+        for &(ptr, layout) in blocks.iter().skip(1).step_by(2) {
+            if !ptr.is_null() {
+                unsafe { allocator.dealloc(ptr, layout) };
+            }
+        }
+        for (i, &(ptr, layout)) in blocks.iter().enumerate().step_by(2) {
+            if !ptr.is_null() {
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, layout.size()) };
+                assert!(bytes.iter().all(|&b| b == i as u8));
+                unsafe { allocator.dealloc(ptr, layout) };
+            }
+        }
+
+        assert_eq!(allocator.lock().free_bytes(), free_before);
+        assert_eq!(allocator.lock().largest_free_block(), largest_before);
+    }
+
+    #[test_case]
+    fn realloc_grows_into_free_buddy() {
+        let (_heap, allocator) = fresh_allocator(TEST_HEAP_CAPACITY);
+        let free_before = allocator.lock().free_bytes();
+
+        // 分割されたばかりのブロックの後ろ半分は空いているので、その場で 4 段まで広げられる
+        let layout = Layout::from_size_align(MIN_BLOCK_SIZE, MIN_BLOCK_SIZE).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        unsafe { ptr::write_bytes(ptr, 0xab, MIN_BLOCK_SIZE) };
+        let new_size = MIN_BLOCK_SIZE << 4;
+        let grown = unsafe { allocator.realloc(ptr, layout, new_size) };
+        assert_eq!(grown, ptr);
+        assert_eq!(allocator.lock().free_bytes(), free_before - new_size);
+
+        // 後ろのバディが使われていればコピーする
+        let blocker = unsafe { allocator.alloc(layout) };
+        assert_eq!(blocker as usize, ptr as usize + new_size);
+        let new_layout = Layout::from_size_align(new_size, MIN_BLOCK_SIZE).unwrap();
+        let moved = unsafe { allocator.realloc(grown, new_layout, new_size * 2) };
+        assert_ne!(moved, grown);
+        let bytes = unsafe { core::slice::from_raw_parts(moved, MIN_BLOCK_SIZE) };
+        assert!(bytes.iter().all(|&b| b == 0xab));
+
+        unsafe {
+            allocator.dealloc(blocker, layout);
+            allocator.dealloc(
+                moved,
+                Layout::from_size_align(new_size * 2, MIN_BLOCK_SIZE).unwrap(),
+            );
+        }
+        assert_eq!(allocator.lock().free_bytes(), free_before);
+    }
+
+    #[test_case]
+    fn extend_moves_bitmap() {
+        // 小さなヒープから始めて、ビットマップの移し替えが何度も起きるまで拡張する
+        let (_heap, allocator) = fresh_allocator(4096);
+        let mut size = 4096;
+        while size < TEST_HEAP_CAPACITY {
+            unsafe { allocator.lock().extend(4096) };
+            size += 4096;
+        }
+
+        // ビットマップ以外はすべて空きブロックになっている
+        let free = allocator.lock().free_bytes();
+        assert!(free > TEST_HEAP_CAPACITY - TEST_HEAP_CAPACITY / 64);
+
+        let layout = Layout::from_size_align(TEST_HEAP_CAPACITY / 4, 4096).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, layout) };
+        assert_eq!(allocator.lock().free_bytes(), free);
+    }
+}
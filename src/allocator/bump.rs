@@ -3,7 +3,7 @@ use core::{
     ptr,
 };
 
-use super::{align_up, Locked};
+use super::{align_up, ExtendHeap, Locked};
 
 pub struct BumpAllocator {
     heap_start: usize,
@@ -27,30 +27,58 @@ impl BumpAllocator {
     /// 呼び出し元は、与えたメモリ領域が未使用であることを保証しなければならない。
     /// また、このメソッドは一度しか呼ばれてはならない。
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.heap_start = heap_start;
-        self.heap_end = heap_start + heap_size;
-        self.next = heap_start;
+        self.set_region(heap_start, heap_size);
     }
-}
 
-unsafe impl GlobalAlloc for Locked<BumpAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut bump = self.lock();
+    /// 切り出す対象を新しい領域に切り替える。Arena がチャンクを追加するたびに呼ぶ
+    ///
+    /// `bump` で古い領域から切り出した割り当てはそのまま使え、このアロケータはもう触れない。
+    /// GlobalAlloc として割り当て中のものがあると、解放時に新しい領域の先頭へ戻ってしまうので、
+    /// そのような割り当てが残っていてはならない。
+    /// 呼び出し元は、新しい領域が未使用であることを保証しなければならない。
+    pub(super) unsafe fn set_region(&mut self, start: usize, size: usize) {
+        debug_assert_eq!(
+            self.allocations, 0,
+            "switching regions with live allocations"
+        );
+        self.heap_start = start;
+        self.heap_end = start + size;
+        self.next = start;
+    }
 
-        let alloc_start = align_up(bump.next, layout.align());
+    /// 空き領域の先頭から layout の領域を切り出す。足りなければ null を返す
+    pub(super) fn bump(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
         let alloc_end = match alloc_start.checked_add(layout.size()) {
             Some(end) => end,
             None => return ptr::null_mut(),
         };
 
-        if alloc_end as usize > bump.heap_end {
+        if alloc_end > self.heap_end {
             ptr::null_mut() // メモリ不足
         } else {
-            bump.next = alloc_end as usize;
-            bump.allocations += 1;
+            self.next = alloc_end;
             alloc_start as *mut u8
         }
     }
+}
+
+impl ExtendHeap for BumpAllocator {
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+    }
+}
+
+unsafe impl GlobalAlloc for Locked<BumpAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut bump = self.lock();
+
+        let ptr = bump.bump(layout);
+        if !ptr.is_null() {
+            bump.allocations += 1;
+        }
+        ptr
+    }
 
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
         let mut bump = self.lock();
@@ -0,0 +1,64 @@
+//! カーネルに依存しないアロケータの共通部分
+//!
+//! ホスト上のテスト (`host_tests`) からもこのファイルを直接読み込むため、
+//! x86_64 やカーネルのモジュールには依存させないこと。
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+};
+
+// Trait 実装用のラッパー
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+/// ヒープの末尾に領域を追加できるアロケータ
+pub trait ExtendHeap {
+    /// ヒープの末尾を by バイト拡張する。
+    ///
+    /// 呼び出し元は、拡張する領域がマップ済みかつ未使用であることを保証しなければならない。
+    unsafe fn extend(&mut self, by: usize);
+}
+
+pub(crate) fn align_up(addr: usize, align: usize) -> usize {
+    // align - 1 は align を満たす bit よりも下位がすべて 1 である数値
+    // つまり、!(align - 1) は align を満たす bit より上位がすべて 1 である数値
+    //
+    // addr & !(align - 1) で、addr 以下で最も近いアラインメントを満たす下向きのアラインができる
+    // 予め対象を (addr + (align - 1)) と加算しておくことで、1アラインメント分ズラした上で計算する
+    // -> 上向きのアラインを得る
+    (addr + align - 1) & !(align - 1)
+}
+
+/// 新しい領域を割り当ててデータをコピーし、元の領域を解放する
+///
+/// その場で領域を伸縮できないときの realloc の実装として使う。
+pub(crate) unsafe fn realloc_by_copy<A: GlobalAlloc>(
+    allocator: &A,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize,
+) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        allocator.dealloc(ptr, layout);
+    }
+
+    new_ptr
+}
@@ -0,0 +1,214 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem, ptr, slice,
+};
+
+use spin::Mutex;
+
+use super::align_up;
+
+/// 割り当て中のブロックのヘッダに書き込む値
+const MAGIC_ALLOCATED: u64 = 0xA110_CA7E_D00D_F00D;
+/// 解放済みのブロックのヘッダに書き込む値
+const MAGIC_FREED: u64 = 0xF1EE_D0DE_DEAD_BEEF;
+
+/// ユーザー領域の前後に置くガードバイト
+const GUARD_BYTE: u8 = 0xFD;
+/// 解放したメモリを埋めるバイト
+const POISON_BYTE: u8 = 0xDD;
+/// 割り当て直後のメモリを埋めるバイト
+const UNINIT_BYTE: u8 = 0xCD;
+
+/// ユーザー領域の前後に置くガード領域の最小サイズ
+const RED_ZONE_SIZE: usize = 16;
+
+/// 解放後すぐには内部のアロケータに返さずに保持しておくブロックの数
+pub const QUARANTINE_LEN: usize = 64;
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+}
+
+/// ユーザーのレイアウトに対して、内部のアロケータから確保するブロックの配置
+struct BlockLayout {
+    /// ブロック先頭からユーザー領域までのオフセット
+    front: usize,
+    /// 内部のアロケータに要求するレイアウト
+    inner: Layout,
+}
+
+impl BlockLayout {
+    fn new(layout: Layout) -> Self {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let front = align_up(mem::size_of::<Header>() + RED_ZONE_SIZE, align);
+        let size = front + layout.size() + RED_ZONE_SIZE;
+
+        BlockLayout {
+            front,
+            inner: Layout::from_size_align(size, align).expect("debug heap layout overflow"),
+        }
+    }
+}
+
+/// ガード領域と解放済みメモリの汚染によってメモリ破壊を検出するデバッグ用ラッパー
+///
+/// 各割り当ての前後にガードバイトを置き、解放時にそれが書き換えられていればパニックする。
+/// 解放したメモリはポイズン値で埋めて一定数を隔離しておき、内部のアロケータに返す前に
+/// ポイズン値が保たれているかを確認することで use-after-free を検出する。
+///
+/// 検出できるのは、ブロックが隔離されている間 (その後に QUARANTINE_LEN 回解放されるまで) だけ。
+/// 隔離から押し出されたブロックは再利用されるので、それ以降の二重解放や書き込みは、
+/// 再利用したブロックのヘッダやガードの破壊として見つかるか、見逃される。
+/// また、書き込みは押し出されるときに検出されるので、パニックは書き込んだ時点より後になる。
+pub struct DebugHeap<A> {
+    inner: A,
+    quarantine: Mutex<Quarantine>,
+}
+
+struct Quarantine {
+    blocks: [Option<(usize, Layout)>; QUARANTINE_LEN],
+    next: usize,
+}
+
+impl<A> DebugHeap<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugHeap {
+            inner,
+            quarantine: Mutex::new(Quarantine {
+                blocks: [None; QUARANTINE_LEN],
+                next: 0,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe fn fill(start: usize, len: usize, byte: u8) {
+    ptr::write_bytes(start as *mut u8, byte, len);
+}
+
+unsafe fn find_mismatch(start: usize, len: usize, byte: u8) -> Option<usize> {
+    slice::from_raw_parts(start as *const u8, len)
+        .iter()
+        .position(|&b| b != byte)
+        .map(|offset| start + offset)
+}
+
+/// ブロックの先頭からユーザー領域の末尾のガードまでがポイズン値で埋まっていることを確認する
+unsafe fn check_poison(block: usize, layout: Layout) {
+    let block_layout = BlockLayout::new(layout);
+    let body = block + mem::size_of::<Header>();
+    let len = block_layout.inner.size() - mem::size_of::<Header>();
+
+    if let Some(addr) = find_mismatch(body, len, POISON_BYTE) {
+        panic!(
+            "use after free detected: {:#x} was written after being freed ({:?} at {:#x})",
+            addr,
+            layout,
+            block + block_layout.front
+        );
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let block_layout = BlockLayout::new(layout);
+        let block = self.inner.alloc(block_layout.inner) as usize;
+        if block == 0 {
+            return ptr::null_mut();
+        }
+
+        let user = block + block_layout.front;
+        (block as *mut Header).write(Header {
+            magic: MAGIC_ALLOCATED,
+            size: layout.size(),
+        });
+
+        let header_end = block + mem::size_of::<Header>();
+        fill(header_end, user - header_end, GUARD_BYTE);
+        fill(user, layout.size(), UNINIT_BYTE);
+        fill(user + layout.size(), RED_ZONE_SIZE, GUARD_BYTE);
+
+        user as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let block_layout = BlockLayout::new(layout);
+        let user = ptr as usize;
+        let block = user - block_layout.front;
+        let header = &mut *(block as *mut Header);
+
+        match header.magic {
+            MAGIC_ALLOCATED => {}
+            MAGIC_FREED => panic!("double free of {:?} at {:#x}", layout, user),
+            _ => panic!(
+                "freeing {:?} at {:#x}: block header is corrupted or was not allocated",
+                layout, user
+            ),
+        }
+
+        assert_eq!(
+            header.size,
+            layout.size(),
+            "freeing {:?} at {:#x} with a layout different from the allocation",
+            layout,
+            user
+        );
+
+        let header_end = block + mem::size_of::<Header>();
+        if let Some(addr) = find_mismatch(header_end, user - header_end, GUARD_BYTE) {
+            panic!(
+                "heap buffer underflow: guard byte {:#x} overwritten ({:?} at {:#x})",
+                addr, layout, user
+            );
+        }
+        if let Some(addr) = find_mismatch(user + layout.size(), RED_ZONE_SIZE, GUARD_BYTE) {
+            panic!(
+                "heap buffer overflow: guard byte {:#x} overwritten ({:?} at {:#x})",
+                addr, layout, user
+            );
+        }
+
+        header.magic = MAGIC_FREED;
+        fill(
+            header_end,
+            block_layout.inner.size() - mem::size_of::<Header>(),
+            POISON_BYTE,
+        );
+
+        // 隔離領域に入れ、押し出された最も古いブロックを内部のアロケータに返す
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let index = quarantine.next;
+            quarantine.next = (index + 1) % QUARANTINE_LEN;
+            quarantine.blocks[index].replace((block, layout))
+        };
+
+        if let Some((block, layout)) = evicted {
+            check_poison(block, layout);
+            self.inner
+                .dealloc(block as *mut u8, BlockLayout::new(layout).inner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[test_case]
+    fn freed_memory_is_poisoned() {
+        let ptr = Box::into_raw(Box::new([UNINIT_BYTE; 32])) as *mut u8;
+        unsafe { drop(Box::from_raw(ptr as *mut [u8; 32])) };
+
+        // 隔離されている間は内部のアロケータに返らないので、ポイズン値が残っている
+        let bytes = unsafe { slice::from_raw_parts(ptr, 32) };
+        assert!(bytes.iter().all(|&b| b == POISON_BYTE));
+    }
+}
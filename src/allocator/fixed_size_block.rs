@@ -4,7 +4,7 @@ use core::{
     ptr::{self, NonNull},
 };
 
-use super::Locked;
+use super::{realloc_by_copy, ExtendHeap, Locked};
 
 struct ListNode {
     next: Option<&'static mut ListNode>,
@@ -15,8 +15,33 @@ struct ListNode {
 /// (ブロックのアラインメントとしても使うため)
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+/// サイズクラスごとにフリーリストへ保持しておく最大のバイト数
+/// これを超えて解放されたブロックはフォールバックアロケータへ直接返す
+const MAX_CACHED_BYTES_PER_CLASS: usize = 16 * 1024;
+
+/// サイズクラスごとのブロック数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    /// 割り当て中のブロック数
+    pub allocated: usize,
+    /// フリーリストにあるブロック数
+    pub free: usize,
+}
+
+/// FixedSizeBlockAllocator の使用状況
+#[derive(Debug, Clone, Copy)]
+pub struct FixedSizeBlockStats {
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    /// フォールバックアロケータで使用中のバイト数 (サイズクラスのブロックを含む)
+    pub fallback_used: usize,
+    /// フォールバックアロケータの空きバイト数
+    pub fallback_free: usize,
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    class_counts: [(usize, usize); BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
 }
 
@@ -25,6 +50,7 @@ impl FixedSizeBlockAllocator {
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
+            class_counts: [(0, 0); BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
         }
     }
@@ -35,7 +61,60 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    /// サイズクラスごとのブロック数とフォールバックアロケータの使用量を返す
+    pub fn stats(&self) -> FixedSizeBlockStats {
+        let mut size_classes = [SizeClassStats {
+            block_size: 0,
+            allocated: 0,
+            free: 0,
+        }; BLOCK_SIZES.len()];
+
+        for (index, class) in size_classes.iter_mut().enumerate() {
+            let (allocated, free) = self.class_counts[index];
+            *class = SizeClassStats {
+                block_size: BLOCK_SIZES[index],
+                allocated,
+                free,
+            };
+        }
+
+        FixedSizeBlockStats {
+            size_classes,
+            fallback_used: self.fallback_allocator.used(),
+            fallback_free: self.fallback_allocator.free(),
+        }
+    }
+
+    /// サイズクラスのリストにある空きブロックをすべてフォールバックアロケータに返却する。
+    ///
+    /// 返却したバイト数を返す。
+    pub fn reclaim(&mut self) -> usize {
+        let mut reclaimed = 0;
+
+        for index in 0..BLOCK_SIZES.len() {
+            while let Some(node) = self.list_heads[index].take() {
+                self.list_heads[index] = node.next.take();
+
+                let ptr = NonNull::from(node).cast::<u8>();
+                unsafe { self.fallback_allocator.deallocate(ptr, block_layout(index)) };
+                reclaimed += BLOCK_SIZES[index];
+            }
+            self.class_counts[index].1 = 0;
+        }
+
+        reclaimed
+    }
+
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // サイズクラスのリストに溜まっているブロックを返却してから再試行する
+        if self.reclaim() == 0 {
+            return ptr::null_mut();
+        }
+
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
@@ -43,6 +122,19 @@ impl FixedSizeBlockAllocator {
     }
 }
 
+impl ExtendHeap for FixedSizeBlockAllocator {
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+}
+
+/// サイズクラスのブロックをフォールバックアロケータから割り当てるときのレイアウトを返す
+fn block_layout(index: usize) -> Layout {
+    // ブロックサイズをアラインメントとしても使う
+    let block_size = BLOCK_SIZES[index];
+    Layout::from_size_align(block_size, block_size).unwrap()
+}
+
 /// 与えられたレイアウトに対して適切なブロックサイズを選び、
 /// そのインデックスを返す。
 fn list_index(layout: &Layout) -> Option<usize> {
@@ -63,16 +155,17 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                     Some(node) => {
                         // フリーリストの先頭ブロックを消費する
                         allocator.list_heads[index] = node.next.take();
+                        allocator.class_counts[index].0 += 1;
+                        allocator.class_counts[index].1 -= 1;
                         node as *mut ListNode as *mut u8
                     }
                     None => {
                         // リストにブロックがないので新しいブロックを割り当てる
-                        let block_size = BLOCK_SIZES[index];
-
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-
-                        allocator.fallback_alloc(layout)
+                        let ptr = allocator.fallback_alloc(block_layout(index));
+                        if !ptr.is_null() {
+                            allocator.class_counts[index].0 += 1;
+                        }
+                        ptr
                     }
                 }
             }
@@ -84,6 +177,17 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
         let mut allocator = self.lock();
 
         match list_index(&layout) {
+            Some(index)
+                if allocator.class_counts[index].1 * BLOCK_SIZES[index]
+                    >= MAX_CACHED_BYTES_PER_CLASS =>
+            {
+                // リストに十分なブロックが溜まっているので、フォールバックアロケータに返す
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator
+                    .fallback_allocator
+                    .deallocate(ptr, block_layout(index));
+                allocator.class_counts[index].0 -= 1;
+            }
             Some(index) => {
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
@@ -96,6 +200,8 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 let new_node_ptr = ptr as *mut ListNode;
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                allocator.class_counts[index].0 -= 1;
+                allocator.class_counts[index].1 += 1;
             }
             None => {
                 let ptr = NonNull::new(ptr).unwrap();
@@ -103,4 +209,63 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+
+        match (list_index(&layout), list_index(&new_layout)) {
+            // 同じサイズクラスのブロックにそのまま収まる
+            (Some(old_index), Some(new_index)) if old_index == new_index => ptr,
+            _ => realloc_by_copy(self, ptr, layout, new_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{test_heap::TestHeap, HEAP_SIZE};
+
+    fn fresh_allocator() -> (TestHeap, Locked<FixedSizeBlockAllocator>) {
+        let heap = TestHeap::take(HEAP_SIZE);
+        let allocator = Locked::new(FixedSizeBlockAllocator::new());
+        unsafe { allocator.lock().init(heap.start(), heap.size()) };
+        (heap, allocator)
+    }
+
+    #[test_case]
+    fn large_allocation_after_small_frees() {
+        let (_heap, allocator) = fresh_allocator();
+        let small = Layout::from_size_align(16, 8).unwrap();
+
+        // ヒープが尽きるまで小さなブロックを割り当てる
+        // 各ブロックに直前のブロックのアドレスを書き込んで連結しておく
+        let mut last: *mut usize = ptr::null_mut();
+        loop {
+            let ptr = unsafe { allocator.alloc(small) } as *mut usize;
+            if ptr.is_null() {
+                break;
+            }
+            unsafe { ptr.write(last as usize) };
+            last = ptr;
+        }
+
+        while !last.is_null() {
+            let prev = unsafe { last.read() } as *mut usize;
+            unsafe { allocator.dealloc(last as *mut u8, small) };
+            last = prev;
+        }
+
+        let index = list_index(&small).unwrap();
+        let cached = allocator.lock().stats().size_classes[index].free;
+        assert!(cached * BLOCK_SIZES[index] <= MAX_CACHED_BYTES_PER_CLASS);
+
+        // リストに残ったブロックも回収されるので、ヒープの大部分を一度に確保できる
+        let large = Layout::from_size_align(HEAP_SIZE - 4096, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(large) };
+        assert!(!ptr.is_null());
+        assert_eq!(allocator.lock().stats().size_classes[index].free, 0);
+
+        unsafe { allocator.dealloc(ptr, large) };
+    }
 }
@@ -0,0 +1,243 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::{
+    structures::paging::{PageSize, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+use super::{align_up, oom, ExtendHeap, Locked, HEAP_MAX_SIZE, HEAP_PAGE_FLAGS};
+use crate::memory;
+
+/// 拡張後のヒープの末尾を揃える境界。2MiB のページでマップできるようにする
+const GROW_ALIGN: usize = Size2MiB::SIZE as usize;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+/// メモリ不足のときに解放して割り当てを続けるための予備領域のサイズ
+const RESERVE_SIZE: usize = 64 * 1024;
+
+/// ヒープを拡張できなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GrowError {
+    /// 上限に達したか、フレームが尽きた (ページテーブルの登録前も含む)
+    Exhausted,
+    /// KERNEL_MEMORY のロックが保持されていた
+    KernelMemoryLocked,
+}
+
+struct HeapRange {
+    start: usize,
+    /// 現在マップされているサイズ
+    size: usize,
+    /// 拡張できる上限のサイズ
+    limit: usize,
+}
+
+/// 内部のアロケータが割り当てに失敗したとき、ヒープの末尾に新しいページをマップして再試行するラッパー
+///
+/// ページのマップには `memory::init_kernel_memory` で登録されたページテーブルとフレームアロケータを使う。
+/// 登録前や上限に達した後は、予備領域があればそれを解放して再試行し、それでも足りなければ失敗する。
+///
+/// KERNEL_MEMORY のロックを保持したまま割り当ててはいけない。ヒープを拡張できないので、
+/// メモリが残っていても失敗する。debug ビルドでは割り当てのたびにこれを検査する。
+pub struct GrowableHeap<A> {
+    allocator: Locked<A>,
+    range: Mutex<HeapRange>,
+    /// 予備領域のアドレス。確保していなければ 0
+    reserve: AtomicUsize,
+    /// KERNEL_MEMORY のロックのせいで拡張できなかった回数
+    blocked_grows: AtomicUsize,
+}
+
+impl<A> GrowableHeap<A> {
+    pub const fn new(allocator: A) -> Self {
+        GrowableHeap {
+            allocator: Locked::new(allocator),
+            range: Mutex::new(HeapRange {
+                start: 0,
+                size: 0,
+                limit: HEAP_MAX_SIZE,
+            }),
+            reserve: AtomicUsize::new(0),
+            blocked_grows: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<A> {
+        self.allocator.lock()
+    }
+
+    /// 初期化時にマップ済みのヒープ範囲を記録する
+    pub fn set_mapped_range(&self, heap_start: usize, heap_size: usize) {
+        let mut range = self.range.lock();
+        range.start = heap_start;
+        range.size = heap_size;
+    }
+
+    /// 拡張できる上限のサイズを設定する
+    pub fn set_limit(&self, limit: usize) {
+        // ページ単位で拡張するので、上限もページ境界に切り詰めておく
+        self.range.lock().limit = limit.min(HEAP_MAX_SIZE) & !(PAGE_SIZE - 1);
+    }
+
+    /// 現在マップされているヒープのサイズを返す
+    pub fn size(&self) -> usize {
+        self.range.lock().size
+    }
+
+    /// KERNEL_MEMORY のロックが保持されていたためにヒープを拡張できなかった回数を返す
+    pub fn blocked_grows(&self) -> usize {
+        self.blocked_grows.load(Ordering::Relaxed)
+    }
+}
+
+impl<A: ExtendHeap> GrowableHeap<A> {
+    /// layout の割り当てのためにヒープを拡張する。一バイトも拡張できなければ理由を返す
+    fn grow(&self, layout: Layout) -> Result<(), GrowError> {
+        let mut range = self.range.lock();
+
+        let available = range.limit.saturating_sub(range.size);
+        let required = layout.size().saturating_add(layout.align());
+        // 次の 2MiB 境界まで拡張する。一度揃えば以降は 2MiB 単位になる
+        let heap_end = range.start + range.size;
+        let target = heap_end
+            .saturating_add(required)
+            .min(range.start + range.limit);
+        let grow_size = align_up(target, GROW_ALIGN)
+            .saturating_sub(heap_end)
+            .min(available);
+
+        let heap_end = VirtAddr::new(heap_end as u64);
+        let mut mapped = 0;
+        let locked = memory::try_with_kernel_memory(|memory| {
+            // 境界の揃った 2MiB 以上の範囲には大きなページを使う
+            while mapped < grow_size {
+                match memory.map_new_chunk(
+                    heap_end + mapped,
+                    (grow_size - mapped) as u64,
+                    HEAP_PAGE_FLAGS,
+                ) {
+                    Ok(size) => mapped += size as usize,
+                    Err(_) => break,
+                }
+            }
+        })
+        .is_none()
+            && memory::kernel_memory_locked();
+
+        if locked {
+            self.blocked_grows.fetch_add(1, Ordering::Relaxed);
+            return Err(GrowError::KernelMemoryLocked);
+        }
+        if mapped == 0 {
+            return Err(GrowError::Exhausted);
+        }
+
+        unsafe { self.allocator.lock().extend(mapped) };
+        range.size += mapped;
+        Ok(())
+    }
+}
+
+impl<A: ExtendHeap> GrowableHeap<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    /// 予備領域がなければ確保する
+    pub fn fill_reserve(&self) {
+        if self.reserve.load(Ordering::Acquire) != 0 {
+            return;
+        }
+
+        // 確保できなくてもメモリ不足としては扱わない
+        let ptr = match self.alloc_growing(reserve_layout(), || unsafe {
+            self.allocator.alloc(reserve_layout())
+        }) {
+            Ok(ptr) => ptr,
+            Err(_) => return,
+        };
+        if self
+            .reserve
+            .compare_exchange(0, ptr as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            unsafe { self.allocator.dealloc(ptr, reserve_layout()) };
+        }
+    }
+
+    /// 予備領域を解放する。解放できなければ false を返す
+    fn release_reserve(&self) -> bool {
+        match self.reserve.swap(0, Ordering::AcqRel) {
+            0 => false,
+            reserve => {
+                unsafe { self.allocator.dealloc(reserve as *mut u8, reserve_layout()) };
+                true
+            }
+        }
+    }
+
+    /// 割り当てに成功するか、これ以上拡張できなくなるまでヒープを広げながら f を再試行する
+    fn alloc_growing(
+        &self,
+        layout: Layout,
+        mut f: impl FnMut() -> *mut u8,
+    ) -> Result<*mut u8, GrowError> {
+        loop {
+            let ptr = f();
+            if !ptr.is_null() {
+                return Ok(ptr);
+            }
+            self.grow(layout)?;
+        }
+    }
+
+    /// alloc_growing でも割り当てられなければ、予備領域を解放して f を再試行する
+    fn retry(&self, layout: Layout, mut f: impl FnMut() -> *mut u8) -> *mut u8 {
+        debug_assert!(
+            !memory::kernel_memory_locked(),
+            "heap allocation while holding KERNEL_MEMORY"
+        );
+
+        match self.alloc_growing(layout, &mut f) {
+            Ok(ptr) => return ptr,
+            // メモリが尽きたわけではないので、予備領域は使わずに失敗する
+            Err(GrowError::KernelMemoryLocked) => return ptr::null_mut(),
+            Err(GrowError::Exhausted) => {}
+        }
+
+        // 予備領域を使った時点でメモリ不足として扱う
+        oom::record_pressure(layout);
+        let mut ptr: *mut u8 = ptr::null_mut();
+        while ptr.is_null() && self.release_reserve() {
+            ptr = f();
+        }
+        ptr
+    }
+}
+
+unsafe impl<A: ExtendHeap> GlobalAlloc for GrowableHeap<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.retry(layout, || self.allocator.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.allocator.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        self.retry(new_layout, || self.allocator.realloc(ptr, layout, new_size))
+    }
+}
+
+fn reserve_layout() -> Layout {
+    Layout::from_size_align(RESERVE_SIZE, PAGE_SIZE).unwrap()
+}
@@ -1,11 +1,9 @@
 use core::{
     alloc::{GlobalAlloc, Layout},
-    mem, ptr,
+    iter, mem, ptr,
 };
 
-use crate::allocator::align_up;
-
-use super::Locked;
+use super::{align_up, realloc_by_copy, ExtendHeap, Locked};
 
 struct ListNode {
     size: usize,
@@ -26,16 +24,36 @@ impl ListNode {
     }
 }
 
+/// フリー領域の探索方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    /// 割り当て可能な最初の領域を使う
+    FirstFit,
+    /// 割り当て可能な領域のうち最も小さいものを使う
+    BestFit,
+}
+
+/// アドレス順に並べたフリーリストで領域を管理するアロケータ
+///
+/// 解放時に隣接するフリー領域と結合するため、断片化したヒープも解放しきれば元の大きさに戻る。
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_end: usize,
+    strategy: FitStrategy,
 }
 
-// TODO: ブロック結合機能を実装しないとまともに使えない
 impl LinkedListAllocator {
     /// 空のアロケータを新規作成
     pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    /// 探索方法を指定して空のアロケータを新規作成
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
+            strategy,
         }
     }
 
@@ -44,44 +62,159 @@ impl LinkedListAllocator {
     /// ヒープ領域が未使用であることは呼び出し元が保証しなければならない。
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
+        self.heap_end = heap_start + heap_size;
+    }
+
+    pub fn strategy(&self) -> FitStrategy {
+        self.strategy
+    }
+
+    pub fn set_strategy(&mut self, strategy: FitStrategy) {
+        self.strategy = strategy;
+    }
+
+    /// 最も大きいフリー領域のサイズを返す
+    pub fn largest_free_region(&self) -> usize {
+        self.regions().map(|region| region.size).max().unwrap_or(0)
     }
 
-    /// 与えられたメモリ領域をフリーリストの先頭に追加する
+    /// フリーリストをアドレス順にたどるイテレータを返す
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        let mut current = self.head.next.as_deref();
+        iter::from_fn(move || {
+            let region = current?;
+            current = region.next.as_deref();
+            Some(region)
+        })
+    }
+
+    /// 与えられたメモリ領域をアドレス順を保ってフリーリストに追加する
+    ///
+    /// 前後の領域と隣接している場合は結合する。
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
+        // addr より前にある最後の領域 (なければ head) を探す
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // NOTE: head はサイズ 0 のダミーなので結合の対象にしない
+        let has_prev = current.size > 0;
+        assert!(
+            !has_prev || current.end_addr() <= addr,
+            "freed region {:#x} overlaps a free region",
+            addr
+        );
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        match current.next.take() {
+            // 後ろの領域と隣接していれば吸収する
+            Some(next) if addr + size == next.start_addr() => {
+                node.size += next.size;
+                node.next = next.next.take();
+            }
+            Some(next) => {
+                assert!(
+                    addr + size <= next.start_addr(),
+                    "freed region {:#x} overlaps a free region",
+                    addr
+                );
+                node.next = Some(next);
+            }
+            None => {}
+        }
 
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr);
+        if has_prev && current.end_addr() == addr {
+            // 前の領域と隣接しているので、前の領域を拡張する
+            current.size += node.size;
+            current.next = node.next.take();
+        } else {
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr);
+        }
+    }
+
+    /// end の直後にあるフリー領域から additional バイトを切り出す
+    ///
+    /// end から始まるフリー領域が十分に大きければ、その先頭を取り除いて true を返す。
+    unsafe fn take_following(&mut self, end: usize, additional: usize) -> bool {
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < end)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        let region_size = match current.next.as_ref() {
+            Some(next) if next.start_addr() == end => next.size,
+            _ => return false,
+        };
+
+        let excess_size = match region_size.checked_sub(additional) {
+            Some(excess) if excess == 0 || excess >= mem::size_of::<ListNode>() => excess,
+            // 足りないか、残りが小さすぎて ListNode を格納できない
+            _ => return false,
+        };
+
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+
+        if excess_size > 0 {
+            self.add_free_region(end + additional, excess_size);
+        }
+        true
     }
 
     /// 与えられたサイズのフリー領域を探し、リストからそれを取り除く
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
-        let mut current = &mut self.head;
+        let target = {
+            let mut candidates = self
+                .regions()
+                .filter(|region| Self::alloc_from_region(region, size, align).is_ok());
 
-        while let Some(ref mut region) = current.next {
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                // 割り当てできたのでフリーリストから除く
-                let next = region.next.take();
-                let ret = Some((current.next.take().unwrap(), alloc_start));
-                current.next = next;
-                return ret;
-            } else {
-                // 割り当てに失敗したので次の領域を調査
-                current = current.next.as_mut().unwrap();
-            }
+            match self.strategy {
+                FitStrategy::FirstFit => candidates.next(),
+                FitStrategy::BestFit => candidates.min_by_key(|region| region.size),
+            }?
+            .start_addr()
+        };
+
+        // 見つかった領域をフリーリストから除く
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() != target)
+        {
+            current = current.next.as_mut().unwrap();
         }
 
-        // 適した領域が見つからなかった
-        None
+        let region = current.next.take().unwrap();
+        current.next = region.next.take();
+
+        let alloc_start = Self::alloc_from_region(region, size, align).unwrap();
+        Some((region, alloc_start))
     }
 
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+
+        let front_size = alloc_start - region.start_addr();
+        if front_size > 0 && front_size < mem::size_of::<ListNode>() {
+            // アラインメントで生じる前方の隙間に ListNode を格納できるようにずらす
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -114,14 +247,30 @@ impl LinkedListAllocator {
     }
 }
 
+impl ExtendHeap for LinkedListAllocator {
+    unsafe fn extend(&mut self, by: usize) {
+        // 末尾のフリー領域と隣接していれば結合される
+        self.add_free_region(self.heap_end, by);
+        self.heap_end += by;
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let (size, align) = LinkedListAllocator::size_align(layout);
         let mut allocator = self.lock();
 
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
+
+            if alloc_start > region_start {
+                // アラインメントで生じた前方の隙間をフリーリストに登録し直す
+                allocator.add_free_region(region_start, alloc_start - region_start);
+            }
+
+            let excess_size = region_end - alloc_end;
             if excess_size > 0 {
                 // 残領域をフリーリストに登録し直す
                 allocator.add_free_region(alloc_end, excess_size);
@@ -137,4 +286,116 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         let (size, _) = LinkedListAllocator::size_align(layout);
         self.lock().add_free_region(ptr as usize, size)
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let (new_size_adjusted, _) = LinkedListAllocator::size_align(new_layout);
+        let addr = ptr as usize;
+
+        {
+            let mut allocator = self.lock();
+
+            if new_size_adjusted == old_size {
+                return ptr;
+            }
+
+            if new_size_adjusted < old_size {
+                // 縮小: 末尾を ListNode として返せるならその場で縮める
+                let tail_size = old_size - new_size_adjusted;
+                if tail_size >= mem::size_of::<ListNode>() {
+                    allocator.add_free_region(addr + new_size_adjusted, tail_size);
+                    return ptr;
+                }
+            } else if allocator.take_following(addr + old_size, new_size_adjusted - old_size) {
+                // 拡大: 直後のフリー領域を取り込めた
+                return ptr;
+            }
+        }
+
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{test_heap::TestHeap, HEAP_SIZE};
+
+    const BLOCK_COUNT: usize = 256;
+
+    fn fresh_allocator(strategy: FitStrategy) -> (TestHeap, Locked<LinkedListAllocator>) {
+        let heap = TestHeap::take(HEAP_SIZE);
+        let allocator = Locked::new(LinkedListAllocator::with_strategy(strategy));
+        unsafe { allocator.lock().init(heap.start(), heap.size()) };
+        (heap, allocator)
+    }
+
+    /// 交互に解放して断片化させたあと、全体が一つの領域に戻ることを確認する
+    fn fragment_and_recover(strategy: FitStrategy) {
+        let (_heap, allocator) = fresh_allocator(strategy);
+        let mut blocks = [(ptr::null_mut(), Layout::new::<u8>()); BLOCK_COUNT];
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let layout = Layout::from_size_align(64 + (i % 5) * 64, 8 << (i % 4)).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % layout.align(), 0);
+            *block = (ptr, layout);
+        }
+
+        // 偶数番目だけを解放しても、隙間は結合されない
+        for &(ptr, layout) in blocks.iter().step_by(2) {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert!(allocator.lock().largest_free_region() < HEAP_SIZE);
+
+        for &(ptr, layout) in blocks.iter().skip(1).step_by(2) {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        assert_eq!(allocator.lock().largest_free_region(), HEAP_SIZE);
+
+        // ヒープ全体を一度に確保できる
+        let layout = Layout::from_size_align(HEAP_SIZE, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    #[test_case]
+    fn first_fit_recovers_full_heap() {
+        fragment_and_recover(FitStrategy::FirstFit);
+    }
+
+    #[test_case]
+    fn best_fit_recovers_full_heap() {
+        fragment_and_recover(FitStrategy::BestFit);
+    }
+
+    #[test_case]
+    fn best_fit_prefers_smallest_region() {
+        let (_heap, allocator) = fresh_allocator(FitStrategy::BestFit);
+        let big = Layout::from_size_align(1024, 8).unwrap();
+        let small = Layout::from_size_align(128, 8).unwrap();
+
+        // [big][sep][small][sep][残り] と並べ、big と small を空ける
+        let a = unsafe { allocator.alloc(big) };
+        let sep1 = unsafe { allocator.alloc(small) };
+        let b = unsafe { allocator.alloc(small) };
+        let sep2 = unsafe { allocator.alloc(small) };
+        unsafe {
+            allocator.dealloc(a, big);
+            allocator.dealloc(b, small);
+        }
+
+        let ptr = unsafe { allocator.alloc(small) };
+        assert_eq!(ptr, b);
+
+        unsafe {
+            allocator.dealloc(ptr, small);
+            allocator.dealloc(sep1, small);
+            allocator.dealloc(sep2, small);
+        }
+        assert_eq!(allocator.lock().largest_free_region(), HEAP_SIZE);
+    }
 }
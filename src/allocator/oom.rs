@@ -0,0 +1,82 @@
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
+/// ヒープを使い果たしたときの振る舞い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OomPolicy {
+    /// 割り当てに失敗したらパニックする (既定)
+    Panic,
+    /// 予備領域を解放して割り当てを続け、メモリが回復するまで新しいタスクを受け付けない
+    RefuseNewWork,
+    /// RefuseNewWork に加えて、最もメモリを使っているタスクを終了させる
+    KillLargestTask,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(OomPolicy::Panic as u8);
+static PRESSURE: AtomicBool = AtomicBool::new(false);
+static KILL_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// メモリ不足になった時点で割り当て中だったバイト数
+static LIVE_AT_PRESSURE: AtomicUsize = AtomicUsize::new(0);
+
+/// OOM ポリシーを設定する
+///
+/// Panic 以外のポリシーでは、割り当てに失敗したときのための予備領域をヒープに確保しておく。
+pub fn set_policy(policy: OomPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+    if policy != OomPolicy::Panic {
+        super::heap().fill_reserve();
+    }
+}
+
+pub fn policy() -> OomPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => OomPolicy::Panic,
+        1 => OomPolicy::RefuseNewWork,
+        _ => OomPolicy::KillLargestTask,
+    }
+}
+
+/// メモリ不足の状態が続いているかを返す
+///
+/// 割り当て中のバイト数がメモリ不足になった時点の 3/4 を下回ると回復したとみなし、予備領域を確保し直す。
+pub fn under_pressure() -> bool {
+    if !PRESSURE.load(Ordering::Acquire) {
+        return false;
+    }
+
+    let threshold = LIVE_AT_PRESSURE.load(Ordering::Relaxed) / 4 * 3;
+    if super::live_bytes() > threshold {
+        return true;
+    }
+
+    PRESSURE.store(false, Ordering::Release);
+    if policy() != OomPolicy::Panic {
+        super::heap().fill_reserve();
+    }
+    false
+}
+
+/// 新しい仕事を断るべきかを返す
+pub fn refuses_new_work() -> bool {
+    policy() != OomPolicy::Panic && under_pressure()
+}
+
+/// タスクの終了が要求されていれば、要求を取り消して true を返す
+pub fn take_kill_request() -> bool {
+    KILL_REQUESTED.swap(false, Ordering::AcqRel)
+}
+
+/// ヒープの拡張も予備領域も尽きかけたときにヒープから呼ばれる
+///
+/// アロケータの中から呼ばれるので、割り当てやロックを行ってはいけない。
+pub(crate) fn record_pressure(_layout: Layout) {
+    if !PRESSURE.swap(true, Ordering::AcqRel) {
+        LIVE_AT_PRESSURE.store(super::live_bytes(), Ordering::Relaxed);
+    }
+    if policy() == OomPolicy::KillLargestTask {
+        KILL_REQUESTED.store(true, Ordering::Release);
+    }
+}
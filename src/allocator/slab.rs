@@ -0,0 +1,317 @@
+use core::{
+    alloc::Layout,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use spin::Mutex;
+
+/// スラブの最小サイズ。ページ単位で確保する
+const MIN_SLAB_SIZE: usize = 4096;
+/// 一つのスラブに最低限格納するオブジェクトの数
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+/// 空になっても返却せずに残しておくスラブの数
+///
+/// 一つのオブジェクトを割り当てては解放するたびに、スラブの確保と返却を繰り返さないようにする。
+const MAX_EMPTY_SLABS: usize = 1;
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+const fn const_align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// スラブの先頭に置かれる管理情報
+struct Slab {
+    prev: Option<NonNull<Slab>>,
+    next: Option<NonNull<Slab>>,
+    free_list: Option<NonNull<FreeObject>>,
+    free_count: usize,
+}
+
+/// 空きオブジェクトの位置に書き込まれるリストノード
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+struct CacheState {
+    /// 空きオブジェクトを持つスラブのリスト
+    partial: Option<NonNull<Slab>>,
+    slabs: usize,
+    /// slabs のうち、すべてのスロットが空いているものの数
+    empty: usize,
+    allocated: usize,
+}
+
+// NOTE: スラブはこのキャッシュのロック下でのみ操作する
+unsafe impl Send for CacheState {}
+
+/// SlabCache の使用状況
+#[derive(Debug, Clone, Copy)]
+pub struct SlabCacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    /// 確保中のスラブの数
+    pub slabs: usize,
+    /// slabs のうち、返却せずに残している空のスラブの数
+    pub empty_slabs: usize,
+    /// 割り当て中のオブジェクトの数
+    pub allocated: usize,
+}
+
+/// 型 T のオブジェクトを固定サイズのスロットで割り当てるキャッシュ
+///
+/// スラブはグローバルアロケータから SLAB_SIZE 単位で確保し、スラブ内の空きスロットをフリーリストで管理する。
+/// すべてのオブジェクトが解放されたスラブは、MAX_EMPTY_SLABS 個までは次の割り当てに備えて残し、
+/// それを超えた分はグローバルアロケータへ返却する。残したスラブは `shrink` で返却できる。
+pub struct SlabCache<T> {
+    name: &'static str,
+    state: Mutex<CacheState>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabCache<T> {
+    const SLOT_ALIGN: usize = max(mem::align_of::<T>(), mem::align_of::<FreeObject>());
+    const SLOT_SIZE: usize = const_align_up(
+        max(mem::size_of::<T>(), mem::size_of::<FreeObject>()),
+        Self::SLOT_ALIGN,
+    );
+    /// スラブ先頭から最初のスロットまでのオフセット
+    const FIRST_SLOT: usize = const_align_up(mem::size_of::<Slab>(), Self::SLOT_ALIGN);
+    /// スラブのサイズ。スラブはこのサイズにアラインされるので、オブジェクトのアドレスからスラブを求められる
+    const SLAB_SIZE: usize = max(
+        (Self::FIRST_SLOT + Self::SLOT_SIZE * MIN_OBJECTS_PER_SLAB).next_power_of_two(),
+        MIN_SLAB_SIZE,
+    );
+    const OBJECTS_PER_SLAB: usize = (Self::SLAB_SIZE - Self::FIRST_SLOT) / Self::SLOT_SIZE;
+
+    pub const fn new(name: &'static str) -> Self {
+        SlabCache {
+            name,
+            state: Mutex::new(CacheState {
+                partial: None,
+                slabs: 0,
+                empty: 0,
+                allocated: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        let state = self.state.lock();
+        SlabCacheStats {
+            name: self.name,
+            object_size: Self::SLOT_SIZE,
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: state.slabs,
+            empty_slabs: state.empty,
+            allocated: state.allocated,
+        }
+    }
+
+    /// value をキャッシュから割り当てたスロットに移動する
+    pub fn alloc(&self, value: T) -> Option<SlabBox<T>> {
+        let ptr = self.allocate()?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(SlabBox { ptr, cache: self })
+    }
+
+    /// 未初期化のスロットを一つ割り当てる
+    pub fn allocate(&self) -> Option<NonNull<T>> {
+        let mut state = self.state.lock();
+
+        let slab = match state.partial {
+            Some(slab) => slab,
+            None => {
+                let slab = Self::new_slab()?;
+                state.slabs += 1;
+                state.empty += 1;
+                Self::push(&mut state, slab);
+                slab
+            }
+        };
+
+        let slab_ref = unsafe { &mut *slab.as_ptr() };
+        if slab_ref.free_count == Self::OBJECTS_PER_SLAB {
+            state.empty -= 1;
+        }
+        let object = slab_ref.free_list.expect("partial slab has no free object");
+        slab_ref.free_list = unsafe { object.as_ref().next };
+        slab_ref.free_count -= 1;
+
+        if slab_ref.free_count == 0 {
+            // 満杯になったスラブは partial リストから外す
+            Self::remove(&mut state, slab);
+        }
+
+        state.allocated += 1;
+        Some(object.cast())
+    }
+
+    /// `allocate` で得たスロットを解放する
+    ///
+    /// 呼び出し元は、ptr がこのキャッシュから割り当てられ、すでにドロップ済みであることを保証しなければならない。
+    pub unsafe fn deallocate(&self, ptr: NonNull<T>) {
+        let mut state = self.state.lock();
+
+        let slab =
+            NonNull::new_unchecked((ptr.as_ptr() as usize & !(Self::SLAB_SIZE - 1)) as *mut Slab);
+        let slab_ref = &mut *slab.as_ptr();
+
+        let object = ptr.cast::<FreeObject>();
+        object.as_ptr().write(FreeObject {
+            next: slab_ref.free_list,
+        });
+        slab_ref.free_list = Some(object);
+        slab_ref.free_count += 1;
+        state.allocated -= 1;
+
+        if slab_ref.free_count == 1 {
+            // 満杯だったスラブに空きができた
+            Self::push(&mut state, slab);
+        }
+
+        if slab_ref.free_count == Self::OBJECTS_PER_SLAB {
+            if state.empty < MAX_EMPTY_SLABS {
+                // 次の割り当てのために残しておく
+                state.empty += 1;
+            } else {
+                Self::remove(&mut state, slab);
+                Self::free_slab(&mut state, slab);
+            }
+        }
+    }
+
+    /// 残している空のスラブをすべてグローバルアロケータに返す
+    pub fn shrink(&self) {
+        let mut state = self.state.lock();
+
+        let mut cursor = state.partial;
+        while let Some(slab) = cursor {
+            let slab_ref = unsafe { &*slab.as_ptr() };
+            cursor = slab_ref.next;
+            if slab_ref.free_count == Self::OBJECTS_PER_SLAB {
+                Self::remove(&mut state, slab);
+                unsafe { Self::free_slab(&mut state, slab) };
+            }
+        }
+        state.empty = 0;
+    }
+
+    /// partial リストから外したスラブをグローバルアロケータに返す
+    unsafe fn free_slab(state: &mut CacheState, slab: NonNull<Slab>) {
+        state.slabs -= 1;
+        alloc::alloc::dealloc(slab.as_ptr() as *mut u8, Self::slab_layout());
+    }
+
+    fn slab_layout() -> Layout {
+        Layout::from_size_align(Self::SLAB_SIZE, Self::SLAB_SIZE).unwrap()
+    }
+
+    /// グローバルアロケータから新しいスラブを確保し、全スロットをフリーリストに繋ぐ
+    fn new_slab() -> Option<NonNull<Slab>> {
+        let start = unsafe { alloc::alloc::alloc(Self::slab_layout()) } as usize;
+        if start == 0 {
+            return None;
+        }
+
+        let mut free_list = None;
+        for index in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = (start + Self::FIRST_SLOT + index * Self::SLOT_SIZE) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free_list }) };
+            free_list = NonNull::new(object);
+        }
+
+        let slab = start as *mut Slab;
+        unsafe {
+            slab.write(Slab {
+                prev: None,
+                next: None,
+                free_list,
+                free_count: Self::OBJECTS_PER_SLAB,
+            });
+        }
+        NonNull::new(slab)
+    }
+
+    /// スラブを partial リストの先頭に追加する
+    fn push(state: &mut CacheState, mut slab: NonNull<Slab>) {
+        unsafe {
+            let slab_ref = slab.as_mut();
+            slab_ref.prev = None;
+            slab_ref.next = state.partial;
+            if let Some(mut next) = state.partial {
+                next.as_mut().prev = Some(slab);
+            }
+        }
+        state.partial = Some(slab);
+    }
+
+    /// スラブを partial リストから取り除く
+    fn remove(state: &mut CacheState, mut slab: NonNull<Slab>) {
+        unsafe {
+            let slab_ref = slab.as_mut();
+            match slab_ref.prev {
+                Some(mut prev) => prev.as_mut().next = slab_ref.next,
+                None => state.partial = slab_ref.next,
+            }
+            if let Some(mut next) = slab_ref.next {
+                next.as_mut().prev = slab_ref.prev;
+            }
+            slab_ref.prev = None;
+            slab_ref.next = None;
+        }
+    }
+}
+
+/// SlabCache から割り当てたオブジェクトを所有するポインタ
+///
+/// ドロップ時にオブジェクトをドロップし、スロットをキャッシュに返す。
+pub struct SlabBox<'a, T> {
+    ptr: NonNull<T>,
+    cache: &'a SlabCache<T>,
+}
+
+impl<T> Deref for SlabBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.deallocate(self.ptr);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SlabBox<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,124 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// ヒープ全体の使用状況
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// 現在割り当て中のバイト数
+    pub live_bytes: usize,
+    /// live_bytes の最大値
+    pub peak_bytes: usize,
+    /// 成功した割り当ての回数
+    pub allocations: usize,
+    /// 解放の回数
+    pub frees: usize,
+    /// 成功した realloc の回数
+    pub reallocations: usize,
+    /// realloc のうち、コピーせずにその場で伸縮できた回数
+    pub in_place_reallocations: usize,
+    /// 現在マップされているヒープのサイズ
+    pub heap_size: usize,
+    /// KERNEL_MEMORY のロックが保持されていたためにヒープを拡張できなかった回数
+    pub blocked_grows: usize,
+    /// グローバルアロケータが FixedSizeBlockAllocator の場合のみ、その内訳
+    pub fixed_size_block: Option<super::fixed_size_block::FixedSizeBlockStats>,
+}
+
+/// 内部のアロケータを通過する割り当てと解放を数えるラッパー
+pub struct StatsAlloc<A> {
+    inner: A,
+    live_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    reallocations: AtomicUsize,
+    in_place_reallocations: AtomicUsize,
+}
+
+impl<A> StatsAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        StatsAlloc {
+            inner,
+            live_bytes: AtomicUsize::new(0),
+            peak_bytes: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            reallocations: AtomicUsize::new(0),
+            in_place_reallocations: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.live_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn allocations(&self) -> usize {
+        self.allocations.load(Ordering::Relaxed)
+    }
+
+    pub fn frees(&self) -> usize {
+        self.frees.load(Ordering::Relaxed)
+    }
+
+    pub fn reallocations(&self) -> usize {
+        self.reallocations.load(Ordering::Relaxed)
+    }
+
+    pub fn in_place_reallocations(&self) -> usize {
+        self.in_place_reallocations.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for StatsAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+
+        if !ptr.is_null() {
+            let live = self.live_bytes.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            self.peak_bytes.fetch_max(live, Ordering::Relaxed);
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+
+        self.live_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            return new_ptr;
+        }
+
+        if new_size >= layout.size() {
+            let grown = new_size - layout.size();
+            let live = self.live_bytes.fetch_add(grown, Ordering::Relaxed) + grown;
+            self.peak_bytes.fetch_max(live, Ordering::Relaxed);
+        } else {
+            self.live_bytes
+                .fetch_sub(layout.size() - new_size, Ordering::Relaxed);
+        }
+
+        self.reallocations.fetch_add(1, Ordering::Relaxed);
+        if new_ptr == ptr {
+            self.in_place_reallocations.fetch_add(1, Ordering::Relaxed);
+        }
+
+        new_ptr
+    }
+}
@@ -0,0 +1,54 @@
+//! アロケータの単体テストで使うヒープ領域
+//!
+//! カーネル内のテストと host_tests の両方から使う。
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// 借りられる領域の最大のサイズ
+pub const TEST_HEAP_CAPACITY: usize = 1024 * 1024;
+
+#[repr(align(4096))]
+struct Buffer([u8; TEST_HEAP_CAPACITY]);
+
+static mut BUFFER: Buffer = Buffer([0; TEST_HEAP_CAPACITY]);
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// 4096 バイト境界に揃ったテスト用のヒープ領域
+///
+/// 領域は一つしかないので、同時に借りられるのは一つだけ。drop すると返却される。
+/// アロケータより先に drop しないよう、テストの間は束縛しておくこと。
+pub struct TestHeap {
+    size: usize,
+}
+
+impl TestHeap {
+    /// 0 で埋めた size バイトの領域を借りる。貸し出し中ならパニックする
+    pub fn take(size: usize) -> Self {
+        assert!(size <= TEST_HEAP_CAPACITY, "test heap is too small");
+        assert!(
+            !IN_USE.swap(true, Ordering::Acquire),
+            "test heap is already in use"
+        );
+
+        let heap = TestHeap { size };
+        unsafe { ptr::write_bytes(heap.start() as *mut u8, 0, size) };
+        heap
+    }
+
+    pub fn start(&self) -> usize {
+        unsafe { ptr::addr_of_mut!(BUFFER.0) as usize }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for TestHeap {
+    fn drop(&mut self) {
+        IN_USE.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,473 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+
+use super::{align_up, realloc_by_copy, ExtendHeap, Locked};
+
+/// ブロックのサイズとアドレスのアラインメント
+const ALIGN: usize = 16;
+const ALIGN_LOG2: usize = 4;
+/// 第二レベルの分割数の log2
+const SL_LOG2: usize = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+/// これより小さなブロックは第一レベル 0 にまとめ、ALIGN 刻みで第二レベルに分ける
+const SMALL_BLOCK_SIZE: usize = 1 << (SL_LOG2 + ALIGN_LOG2);
+const FL_SHIFT: usize = SL_LOG2 + ALIGN_LOG2;
+/// リストで区別できるブロックサイズの上限の log2。これ以上のブロックは最後のリストに入れる
+const FL_MAX: usize = 32;
+const FL_COUNT: usize = FL_MAX - FL_SHIFT + 1;
+
+const HEADER_SIZE: usize = mem::size_of::<BlockHeader>();
+/// 空きブロックのリンクを格納できる最小のブロックサイズ
+const MIN_BLOCK_SIZE: usize = HEADER_SIZE + mem::size_of::<FreeLinks>();
+
+/// 空きブロックであることを示す size のビット
+const FREE_BIT: usize = 1;
+
+/// すべてのブロックの先頭に置かれるヘッダ
+#[repr(C)]
+struct BlockHeader {
+    /// 物理的に直前のブロック。先頭のブロックでは null
+    prev_phys: *mut BlockHeader,
+    /// ヘッダを含むブロックのサイズと FREE_BIT
+    size: usize,
+}
+
+/// 空きブロックのヘッダの直後に書き込まれるリンク
+#[repr(C)]
+struct FreeLinks {
+    prev: Option<NonNull<BlockHeader>>,
+    next: Option<NonNull<BlockHeader>>,
+}
+
+impl BlockHeader {
+    fn size(&self) -> usize {
+        self.size & !FREE_BIT
+    }
+
+    fn is_free(&self) -> bool {
+        self.size & FREE_BIT != 0
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.size = size | (self.size & FREE_BIT);
+    }
+
+    fn set_free(&mut self, free: bool) {
+        if free {
+            self.size |= FREE_BIT;
+        } else {
+            self.size &= !FREE_BIT;
+        }
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// 物理的に直後のブロック。最後のブロックの直後には番兵がある
+    unsafe fn next_phys(&self) -> &'static mut BlockHeader {
+        &mut *((self.addr() + self.size()) as *mut BlockHeader)
+    }
+
+    unsafe fn links(&mut self) -> &mut FreeLinks {
+        &mut *((self.addr() + HEADER_SIZE) as *mut FreeLinks)
+    }
+}
+
+/// Two-Level Segregated Fit アロケータ
+///
+/// 空きブロックをサイズの最上位ビット (第一レベル) とその下の SL_LOG2 ビット (第二レベル) で
+/// 分類したリストで管理する。空きのあるリストはビットマップで引けるので、探索を伴わずに
+/// 割り当てと解放が O(1) で終わる。隣接する空きブロックは解放時にすぐ結合される。
+pub struct TlsfAllocator {
+    fl_bitmap: usize,
+    sl_bitmaps: [usize; FL_COUNT],
+    free_lists: [[Option<NonNull<BlockHeader>>; SL_COUNT]; FL_COUNT],
+    heap_end: usize,
+    /// ヒープ末尾の番兵のアドレス
+    sentinel: usize,
+    free_bytes: usize,
+}
+
+// NOTE: ブロックはこのアロケータのロック下でのみ操作する
+unsafe impl Send for TlsfAllocator {}
+
+impl TlsfAllocator {
+    pub const fn new() -> Self {
+        TlsfAllocator {
+            fl_bitmap: 0,
+            sl_bitmaps: [0; FL_COUNT],
+            free_lists: [[None; SL_COUNT]; FL_COUNT],
+            heap_end: 0,
+            sentinel: 0,
+            free_bytes: 0,
+        }
+    }
+
+    /// 与えられたヒープ境界でアロケータを初期化する。
+    ///
+    /// 呼び出し元はヒープ領域が未使用であることを保証しなければならない。
+    /// また、このメソッドは一度しか呼ばれてはならない。
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        let start = align_up(heap_start, ALIGN);
+        let sentinel = (heap_start + heap_size - HEADER_SIZE) & !(ALIGN - 1);
+        assert!(sentinel >= start + MIN_BLOCK_SIZE, "heap is too small");
+
+        let block = &mut *(start as *mut BlockHeader);
+        block.prev_phys = ptr::null_mut();
+        block.size = sentinel - start;
+        Self::write_sentinel(sentinel, block);
+        self.heap_end = heap_start + heap_size;
+        self.sentinel = sentinel;
+
+        self.insert(block);
+    }
+
+    /// 空きブロックのヘッダを除いた合計バイト数を返す
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// ヒープの末尾にサイズ 0 の使用中のブロックを置き、最後のブロックの next_phys が常に有効になるようにする
+    unsafe fn write_sentinel(addr: usize, prev: &mut BlockHeader) {
+        (addr as *mut BlockHeader).write(BlockHeader {
+            prev_phys: prev,
+            size: 0,
+        });
+    }
+
+    /// 空きブロックを対応するリストの先頭に追加する
+    unsafe fn insert(&mut self, block: &mut BlockHeader) {
+        let (fl, sl) = mapping(block.size());
+        let head = self.free_lists[fl][sl];
+
+        block.set_free(true);
+        *block.links() = FreeLinks {
+            prev: None,
+            next: head,
+        };
+        let block = NonNull::from(block);
+        if let Some(mut head) = head {
+            head.as_mut().links().prev = Some(block);
+        }
+
+        self.free_lists[fl][sl] = Some(block);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmaps[fl] |= 1 << sl;
+        self.free_bytes += block.as_ref().size() - HEADER_SIZE;
+    }
+
+    /// 空きブロックをリストから取り除く
+    unsafe fn remove(&mut self, block: &mut BlockHeader) {
+        let (fl, sl) = mapping(block.size());
+        let FreeLinks { prev, next } = ptr::read(block.links());
+
+        match prev {
+            Some(mut prev) => prev.as_mut().links().next = next,
+            None => self.free_lists[fl][sl] = next,
+        }
+        if let Some(mut next) = next {
+            next.as_mut().links().prev = prev;
+        }
+
+        if self.free_lists[fl][sl].is_none() {
+            self.sl_bitmaps[fl] &= !(1 << sl);
+            if self.sl_bitmaps[fl] == 0 {
+                self.fl_bitmap &= !(1 << fl);
+            }
+        }
+
+        block.set_free(false);
+        self.free_bytes -= block.size() - HEADER_SIZE;
+    }
+
+    /// size バイト以上の空きブロックを一つ取り出す
+    unsafe fn take_suitable(&mut self, size: usize) -> Option<&'static mut BlockHeader> {
+        let (mut fl, sl) = mapping_search(size)?;
+
+        // 同じ第一レベルで sl 以上のリスト、なければより大きな第一レベルのリストを使う
+        let mut sl_map = self.sl_bitmaps[fl] & (!0 << sl);
+        if sl_map == 0 {
+            let fl_map = self.fl_bitmap & (!0 << (fl + 1));
+            if fl_map == 0 {
+                return None;
+            }
+            fl = fl_map.trailing_zeros() as usize;
+            sl_map = self.sl_bitmaps[fl];
+        }
+        let sl = sl_map.trailing_zeros() as usize;
+
+        let block = &mut *self.free_lists[fl][sl]?.as_ptr();
+        self.remove(block);
+        Some(block)
+    }
+
+    /// block の先頭 size バイトを残し、残りが十分に大きければ空きブロックとして切り離す
+    unsafe fn split_tail(&mut self, block: &mut BlockHeader, size: usize) {
+        let rest_size = block.size() - size;
+        if rest_size < MIN_BLOCK_SIZE {
+            return;
+        }
+
+        let rest = &mut *((block.addr() + size) as *mut BlockHeader);
+        rest.prev_phys = block;
+        rest.size = rest_size;
+        block.set_size(size);
+        rest.next_phys().prev_phys = rest;
+
+        self.free_block(rest);
+    }
+
+    /// ブロックを空きにし、前後の空きブロックと結合してからリストに追加する
+    unsafe fn free_block(&mut self, mut block: &'static mut BlockHeader) {
+        let next = block.next_phys();
+        if next.is_free() {
+            self.remove(next);
+            block.set_size(block.size() + next.size());
+        }
+
+        if !block.prev_phys.is_null() && (*block.prev_phys).is_free() {
+            let prev = &mut *block.prev_phys;
+            self.remove(prev);
+            prev.set_size(prev.size() + block.size());
+            block = prev;
+        }
+
+        block.next_phys().prev_phys = block;
+        self.insert(block);
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = match block_size_for(layout.size()) {
+            Some(size) => size,
+            None => return ptr::null_mut(),
+        };
+
+        unsafe {
+            if layout.align() <= ALIGN {
+                let block = match self.take_suitable(size) {
+                    Some(block) => block,
+                    None => return ptr::null_mut(),
+                };
+                self.split_tail(block, size);
+                return (block.addr() + HEADER_SIZE) as *mut u8;
+            }
+
+            // 前側に切り離せるだけの隙間を作れるよう、余分に大きなブロックを探す
+            let search = match size.checked_add(layout.align() + MIN_BLOCK_SIZE) {
+                Some(search) => search,
+                None => return ptr::null_mut(),
+            };
+            let mut block = match self.take_suitable(search) {
+                Some(block) => block,
+                None => return ptr::null_mut(),
+            };
+
+            let mut user = align_up(block.addr() + HEADER_SIZE, layout.align());
+            if user - HEADER_SIZE != block.addr()
+                && user - HEADER_SIZE - block.addr() < MIN_BLOCK_SIZE
+            {
+                user = align_up(block.addr() + HEADER_SIZE + MIN_BLOCK_SIZE, layout.align());
+            }
+
+            let gap = user - HEADER_SIZE - block.addr();
+            if gap > 0 {
+                // 前側の隙間を空きブロックとして返す
+                let aligned = &mut *((block.addr() + gap) as *mut BlockHeader);
+                aligned.prev_phys = block;
+                aligned.size = block.size() - gap;
+                block.set_size(gap);
+                aligned.next_phys().prev_phys = aligned;
+
+                self.free_block(block);
+                block = aligned;
+            }
+
+            self.split_tail(block, size);
+            user as *mut u8
+        }
+    }
+
+    /// ptr の割り当てをその場で new_size バイトに伸縮する。できなければ false を返す
+    unsafe fn resize_in_place(&mut self, ptr: *mut u8, new_size: usize) -> bool {
+        let block = &mut *((ptr as usize - HEADER_SIZE) as *mut BlockHeader);
+        let size = match block_size_for(new_size) {
+            Some(size) => size,
+            None => return false,
+        };
+
+        if size > block.size() {
+            // 直後の空きブロックを吸収して伸長する
+            let next = block.next_phys();
+            if !next.is_free() || block.size() + next.size() < size {
+                return false;
+            }
+            self.remove(next);
+            block.set_size(block.size() + next.size());
+            block.next_phys().prev_phys = block;
+        }
+
+        self.split_tail(block, size);
+        true
+    }
+}
+
+impl ExtendHeap for TlsfAllocator {
+    unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+
+        // 古い番兵の位置から新しいブロックを始め、新しい終端に番兵を置き直す
+        // ブロックを作れるだけの大きさがなければ、次の拡張まで待つ
+        let new_sentinel = (self.heap_end - HEADER_SIZE) & !(ALIGN - 1);
+        if new_sentinel < self.sentinel + MIN_BLOCK_SIZE {
+            return;
+        }
+
+        let block = &mut *(self.sentinel as *mut BlockHeader);
+        block.size = new_sentinel - self.sentinel;
+        Self::write_sentinel(new_sentinel, block);
+        self.sentinel = new_sentinel;
+
+        self.free_block(block);
+    }
+}
+
+/// size バイトの割り当てに使うブロックのサイズを返す
+fn block_size_for(size: usize) -> Option<usize> {
+    let size = size.checked_add(HEADER_SIZE + ALIGN - 1)? & !(ALIGN - 1);
+    Some(size.max(MIN_BLOCK_SIZE))
+}
+
+/// ブロックサイズが属するリストの (第一レベル, 第二レベル) を返す
+///
+/// 1 << FL_MAX 以上のブロックは最後のリストに入れる。そのリストのブロックはすべてリストの
+/// 最小サイズ以上なので、mapping_search で選ばれても要求を満たせる。
+fn mapping(size: usize) -> (usize, usize) {
+    if size < SMALL_BLOCK_SIZE {
+        (0, size / (SMALL_BLOCK_SIZE / SL_COUNT))
+    } else if size >= 1 << FL_MAX {
+        (FL_COUNT - 1, SL_COUNT - 1)
+    } else {
+        let fl = fls(size);
+        let sl = (size >> (fl - SL_LOG2)) ^ SL_COUNT;
+        (fl - FL_SHIFT + 1, sl)
+    }
+}
+
+/// size 以上のブロックだけが入っている最小のリストを返す
+///
+/// 1 << FL_MAX 以上の要求には None を返す。
+fn mapping_search(size: usize) -> Option<(usize, usize)> {
+    let size = if size >= SMALL_BLOCK_SIZE {
+        // 同じリストのブロックがすべて size 以上になるよう、第二レベルの幅だけ切り上げる
+        size.checked_add((1 << (fls(size) - SL_LOG2)) - 1)?
+    } else {
+        size
+    };
+
+    if size >= 1 << FL_MAX {
+        return None;
+    }
+    Some(mapping(size))
+}
+
+/// 最上位ビットの位置
+fn fls(size: usize) -> usize {
+    mem::size_of::<usize>() * 8 - 1 - size.leading_zeros() as usize
+}
+
+unsafe impl GlobalAlloc for Locked<TlsfAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let block = &mut *((ptr as usize - HEADER_SIZE) as *mut BlockHeader);
+        self.lock().free_block(block);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.lock().resize_in_place(ptr, new_size) {
+            return ptr;
+        }
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::allocator::{test_heap::TestHeap, HEAP_SIZE};
+
+    fn fresh_allocator() -> (TestHeap, Locked<TlsfAllocator>) {
+        let heap = TestHeap::take(HEAP_SIZE);
+        let allocator = Locked::new(TlsfAllocator::new());
+        unsafe { allocator.lock().init(heap.start(), heap.size()) };
+        (heap, allocator)
+    }
+
+    /// リスト (fl, sl) に入る最小のブロックサイズ
+    fn list_min_size(fl: usize, sl: usize) -> usize {
+        if fl == 0 {
+            sl * (SMALL_BLOCK_SIZE / SL_COUNT)
+        } else {
+            (SL_COUNT + sl) << (fl + FL_SHIFT - 1 - SL_LOG2)
+        }
+    }
+
+    #[test_case]
+    fn mapping_search_rounds_up() {
+        // 探索で選ばれるリストのブロックは、必ず要求サイズ以上になる
+        for size in (MIN_BLOCK_SIZE..1024 * 1024).step_by(ALIGN) {
+            let (fl, sl) = mapping_search(size).unwrap();
+            assert!(list_min_size(fl, sl) >= size);
+            assert_eq!(mapping(list_min_size(fl, sl)), (fl, sl));
+        }
+    }
+
+    #[test_case]
+    fn oversized_sizes_stay_in_bounds() {
+        // 大きすぎるブロックは最後のリストに入り、同じ大きさの要求は断られる
+        for &size in [1 << FL_MAX, usize::MAX & !(ALIGN - 1)].iter() {
+            assert_eq!(mapping(size), (FL_COUNT - 1, SL_COUNT - 1));
+            assert_eq!(mapping_search(size), None);
+        }
+        assert!(list_min_size(FL_COUNT - 1, SL_COUNT - 1) < 1 << FL_MAX);
+
+        let (_heap, allocator) = fresh_allocator();
+        let layout = Layout::from_size_align(1 << FL_MAX, 8).unwrap();
+        assert!(unsafe { allocator.alloc(layout) }.is_null());
+    }
+
+    #[test_case]
+    fn fragment_and_merge() {
+        let (_heap, allocator) = fresh_allocator();
+        let free_before = allocator.lock().free_bytes();
+
+        let mut blocks = [(ptr::null_mut(), Layout::new::<u8>()); 128];
+        for (i, block) in blocks.iter_mut().enumerate() {
+            let layout = Layout::from_size_align(24 + (i % 7) * 100, 8 << (i % 6)).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % layout.align(), 0);
+            *block = (ptr, layout);
+        }
+
+        for &(ptr, layout) in blocks.iter().step_by(2) {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        for &(ptr, layout) in blocks.iter().skip(1).step_by(2) {
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+
+        // 隣接する空きブロックがすべて結合され、最初の一つのブロックに戻る
+        assert_eq!(allocator.lock().free_bytes(), free_before);
+        let layout = Layout::from_size_align(free_before / 2, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+}
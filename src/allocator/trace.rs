@@ -0,0 +1,211 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::x86_64::_rdtsc,
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use conquer_once::spin::OnceCell;
+use crossbeam_queue::ArrayQueue;
+use futures_util::{task::AtomicWaker, Stream, StreamExt};
+
+use crate::serial_println;
+
+/// リングバッファに保持できるイベントの数
+const EVENT_CAPACITY: usize = 1024;
+
+static EVENTS: OnceCell<ArrayQueue<TraceEvent>> = OnceCell::uninit();
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static CURRENT_TAG: AtomicU64 = AtomicU64::new(0);
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// 出力タスクを起こし済みかどうか。割り当てのたびに起こさないようにする
+static WAKE_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Alloc,
+    Dealloc,
+}
+
+/// グローバルアロケータに届いた一回の割り当てまたは解放
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEvent {
+    pub kind: EventKind,
+    pub addr: usize,
+    pub size: usize,
+    pub align: usize,
+    /// 割り当てを行ったコードを示すタグ (`with_tag` で設定する)
+    pub tag: u64,
+    /// rdtsc の値
+    pub timestamp: u64,
+}
+
+/// ホストで解析しやすいよう、一つのイベントを一行で出力する
+///
+/// `<A|D> <timestamp> <addr> <size> <align> <tag>`
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            EventKind::Alloc => 'A',
+            EventKind::Dealloc => 'D',
+        };
+        write!(
+            f,
+            "{} {} {:#x} {} {} {}",
+            kind, self.timestamp, self.addr, self.size, self.align, self.tag
+        )
+    }
+}
+
+/// トレースを開始する
+///
+/// 初回の呼び出しでリングバッファを確保する。以降、記録中は一切割り当てを行わない。
+pub fn enable() {
+    EVENTS.init_once(|| ArrayQueue::new(EVENT_CAPACITY));
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// トレースを停止する。バッファに残っているイベントはそのまま取り出せる
+pub fn disable() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// バッファが一杯で捨てたイベントの数を返す
+pub fn dropped_events() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// f の中で行われた割り当てにタグを付けて記録する
+pub fn with_tag<R>(tag: u64, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT_TAG.swap(tag, Ordering::Relaxed);
+    let result = f();
+    CURRENT_TAG.store(previous, Ordering::Relaxed);
+    result
+}
+
+/// バッファから最も古いイベントを取り出す
+pub fn pop_event() -> Option<TraceEvent> {
+    EVENTS.try_get().ok()?.pop().ok()
+}
+
+fn record(kind: EventKind, addr: *mut u8, layout: Layout) {
+    if !is_enabled() {
+        return;
+    }
+    let queue = match EVENTS.try_get() {
+        Ok(queue) => queue,
+        Err(_) => return,
+    };
+
+    let event = TraceEvent {
+        kind,
+        addr: addr as usize,
+        size: layout.size(),
+        align: layout.align(),
+        tag: CURRENT_TAG.load(Ordering::Relaxed),
+        timestamp: unsafe { _rdtsc() },
+    };
+    if queue.push(event).is_err() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    if !WAKE_PENDING.swap(true, Ordering::AcqRel) {
+        WAKER.wake();
+    }
+}
+
+/// 内部のアロケータを通過する割り当てと解放をリングバッファに記録するラッパー
+pub struct TraceAlloc<A> {
+    inner: A,
+}
+
+impl<A> TraceAlloc<A> {
+    pub const fn new(inner: A) -> Self {
+        TraceAlloc { inner }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TraceAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record(EventKind::Alloc, ptr, layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(EventKind::Dealloc, ptr, layout);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            // 解析側では解放と割り当ての組として扱う
+            record(EventKind::Dealloc, ptr, layout);
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            record(EventKind::Alloc, new_ptr, new_layout);
+        }
+        new_ptr
+    }
+}
+
+pub struct TraceEventStream {
+    _private: (),
+}
+
+impl TraceEventStream {
+    pub fn new() -> Self {
+        TraceEventStream { _private: () }
+    }
+}
+
+impl Stream for TraceEventStream {
+    type Item = TraceEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some(event) = pop_event() {
+            return Poll::Ready(Some(event));
+        }
+
+        // 取り出せなかったので待つ
+        WAKER.register(&cx.waker());
+        WAKE_PENDING.store(false, Ordering::Release);
+
+        match pop_event() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// 記録されたイベントを SERIAL1 に一行ずつ書き出すタスク
+pub async fn drain_to_serial() {
+    let mut events = TraceEventStream::new();
+    let mut reported_dropped = 0;
+
+    while let Some(event) = events.next().await {
+        let dropped = dropped_events();
+        if dropped != reported_dropped {
+            serial_println!("# dropped {} events", dropped - reported_dropped);
+            reported_dropped = dropped;
+        }
+        serial_println!("{}", event);
+    }
+}
@@ -1,34 +1,34 @@
+use core::ptr;
+
 use lazy_static::lazy_static;
 use x86_64::registers::segmentation::Segment;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
+/// IST で使うスタックの大きさ
+const IST_STACK_SIZE: usize = 4096 * 5;
+const IST_INDICES: [u16; 2] = [DOUBLE_FAULT_IST_INDEX, NMI_IST_INDEX];
 
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
+/// カーネルのメモリが使えるようになるまでの IST のスタック
+///
+/// NOTE: static mut にしないとリードオンリーなページに map されてしまう
+static mut BOOT_STACKS: [[u8; IST_STACK_SIZE]; IST_INDICES.len()] =
+    [[0; IST_STACK_SIZE]; IST_INDICES.len()];
 
-            // NOTE: static mut にしないとリードオンリーなページに map されてしまう
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-
-        tss
-    };
-}
+// init_stacks で IST のスタックを差し替えるので、可変にしておく
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*ptr::addr_of!(TSS) }));
         (
             gdt,
             Selectors {
@@ -48,6 +48,12 @@ pub fn init() {
     use x86_64::instructions::segmentation::CS;
     use x86_64::instructions::tables::load_tss;
 
+    let mut table = unsafe { read_ist() };
+    for (i, &index) in IST_INDICES.iter().enumerate() {
+        table[index as usize] = boot_stack_top(i);
+    }
+    unsafe { write_ist(table) };
+
     GDT.0.load();
 
     unsafe {
@@ -55,3 +61,39 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// 二重フォールトと NMI 用のスタックをガードページ付きで確保し、起動時の静的なスタックと差し替える
+///
+/// 差し替えた後は、スタックが溢れても .bss を壊さずにフォルトになる。
+/// `memory::init_kernel_memory` の後で一度だけ呼ぶ。
+pub fn init_stacks() {
+    let mut table = unsafe { read_ist() };
+    for (i, &index) in IST_INDICES.iter().enumerate() {
+        let entry = &mut table[index as usize];
+        assert_eq!(
+            *entry,
+            boot_stack_top(i),
+            "interrupt stacks already initialized"
+        );
+        *entry = memory::stack::allocate_stack(IST_STACK_SIZE as u64)
+            .expect("failed to allocate an interrupt stack")
+            .leak();
+    }
+
+    // CPU は割り込みのたびに TSS を読むので、ロード済みの TSS を書き換えれば反映される
+    unsafe { write_ist(table) };
+}
+
+fn boot_stack_top(i: usize) -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { ptr::addr_of!(BOOT_STACKS[i]) });
+    stack_start + IST_STACK_SIZE
+}
+
+// TSS は packed なので、参照を作らずにポインタ経由で読み書きする
+unsafe fn read_ist() -> [VirtAddr; 7] {
+    ptr::addr_of!(TSS.interrupt_stack_table).read_unaligned()
+}
+
+unsafe fn write_ist(table: [VirtAddr; 7]) {
+    ptr::addr_of_mut!(TSS.interrupt_stack_table).write_unaligned(table)
+}
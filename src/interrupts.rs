@@ -2,7 +2,7 @@ use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::{gdt, hlt_loop};
+use crate::{gdt, hlt_loop, memory};
 use crate::{print, println};
 
 pub const PIC_1_OFFSET: u8 = 32;
@@ -20,6 +20,9 @@ lazy_static! {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
         }
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interupt_handler);
@@ -43,9 +46,23 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // スタックが溢れてページフォルトの例外フレームを積めなかった場合
+    let addr = Cr2::read();
+    if memory::stack::is_stack_overflow(addr, stack_frame.stack_pointer) {
+        panic!(
+            "EXCEPTION: DOUBLE FAULT (kernel stack overflow at {:?})\n{:#?}",
+            addr, stack_frame
+        );
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION: NMI\n{:#?}", stack_frame);
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
@@ -92,8 +109,21 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    // 予約済みの領域ならフレームを割り当てて再開する
+    let addr = Cr2::read();
+    if memory::demand::handle_page_fault(addr, error_code) {
+        return;
+    }
+    // コピーオンライトのページへの書き込みならフレームをコピーして再開する
+    if memory::cow::handle_page_fault(addr, error_code) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    if memory::stack::is_guard_page(addr) {
+        println!("Kernel stack overflow");
+    }
+    println!("Accessed Address: {:?}", addr);
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hlt_loop();
@@ -5,11 +5,17 @@
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(allocator_api)]
 #![feature(const_mut_refs)]
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
-    panic!("allocation error: {:?}", layout)
+    panic!(
+        "allocation error: {:?} (oom policy: {:?}, blocked grows: {})",
+        layout,
+        allocator::oom::policy(),
+        allocator::stats().blocked_grows
+    )
 }
 
 pub mod allocator;
@@ -21,9 +27,12 @@ pub mod task;
 pub mod vga_buffer;
 
 extern crate alloc;
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
 
 pub fn init() {
+    // 以降にマップするページで NO_EXECUTE を使えるよう、最初に有効にする
+    memory::protect::enable_nx();
     gdt::init();
     interrupts::init_idt();
 
@@ -34,6 +43,24 @@ pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
+/// カーネルのページテーブル、フレームアロケータ、ヒープを初期化し、カーネルのマッピングに W^X を適用する
+///
+/// カーネル本体とすべてのテストはこの順序で初期化するので、W^X を有効にしたまま動くことを確かめられる。
+/// `init` の後に一度だけ呼ぶ。
+pub fn init_memory(boot_info: &'static BootInfo) {
+    use memory::bitmap::BitmapFrameAllocator;
+    use x86_64::VirtAddr;
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::protect::enforce_wx(&mut mapper, &boot_info.memory_map) };
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_kernel_memory(mapper, frame_allocator);
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -72,6 +99,55 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     hlt_loop();
 }
 
+/// パニックすることを確かめるテスト用のパニックハンドラ
+///
+/// パニックメッセージに expected が含まれていれば成功、そうでなければ失敗として終了する。
+pub fn expect_panic_handler(info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = MessageBuffer {
+        bytes: [0; 256],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info);
+
+    if message.contains(expected) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+/// ヒープを使わずにパニックメッセージの先頭を保持する
+struct MessageBuffer {
+    bytes: [u8; 256],
+    len: usize,
+}
+
+impl core::fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len < self.bytes.len() {
+                self.bytes[self.len] = byte;
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageBuffer {
+    fn contains(&self, needle: &str) -> bool {
+        self.bytes[..self.len]
+            .windows(needle.len())
+            .any(|window| window == needle.as_bytes())
+    }
+}
+
 pub enum QemuExitCode {
     Success = 0x10,
     Failed = 0x11,
@@ -87,14 +163,17 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 }
 
 #[cfg(test)]
-use bootloader::{entry_point, BootInfo};
+use bootloader::entry_point;
 
 #[cfg(test)]
 entry_point!(test_kernel_main);
 
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    // ヒープを使う単体テストのために、統合テストと同じくメモリを初期化する
+    init_memory(boot_info);
+
     test_main();
     hlt_loop();
 }
@@ -7,14 +7,11 @@
 extern crate alloc;
 
 use blog_os::{
-    allocator,
-    memory::{self, BootInfoFrameAllocator},
-    println,
+    allocator, gdt, println,
     task::{executor::Executor, keyboard, Task},
 };
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use x86_64::VirtAddr;
 
 entry_point!(kernel_main);
 
@@ -23,12 +20,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     blog_os::init();
 
-    // L4 ページテーブルへアクセス
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // ページテーブルとヒープを初期化し、カーネルのマッピングに W^X を適用する
+    blog_os::init_memory(boot_info);
+    // 二重フォールトと NMI 用のスタックをガードページ付きで確保する
+    gdt::init_stacks();
 
     #[cfg(test)]
     test_main();
@@ -36,6 +31,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
     executor.spawn(Task::new(keyboard::print_keypresses()));
+    // allocator::trace::enable() で記録を始めると、このタスクがシリアルに書き出す
+    executor.spawn(Task::new(allocator::trace::drain_to_serial()));
     executor.run();
 }
 
@@ -1,9 +1,115 @@
+pub mod address_space;
+pub mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod demand;
+pub mod huge;
+pub mod mmio;
+pub mod protect;
+pub mod stack;
+pub mod vma;
+pub mod vmalloc;
+pub mod walk;
+
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{
+    FrameAllocator, OffsetPageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
 use x86_64::PhysAddr;
 use x86_64::{structures::paging::PageTable, VirtAddr};
 
+use self::bitmap::BitmapFrameAllocator;
+use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+
+/// カーネル全体で共有するページテーブルとフレームアロケータ
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+/// カーネルが後から使う仮想アドレス範囲 (先頭, サイズ)
+const KERNEL_RANGES: [(u64, u64); 3] = [
+    (HEAP_START as u64, HEAP_MAX_SIZE as u64),
+    (vma::KERNEL_VMA_START, vma::KERNEL_VMA_SIZE),
+    (stack::KERNEL_STACK_START, stack::KERNEL_STACK_REGION_SIZE),
+];
+
+/// ページテーブルとフレームアロケータを登録し、ヒープの拡張などからも使えるようにする。
+///
+/// カーネルが後から使う範囲の L4 エントリをここで埋めておくので、
+/// これ以降に作ったアドレス空間からも、その範囲に後でマップしたページが見える。
+pub fn init_kernel_memory(
+    mut mapper: OffsetPageTable<'static>,
+    mut frame_allocator: BitmapFrameAllocator,
+) {
+    populate_kernel_l4_entries(&mut mapper, &mut frame_allocator);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut kernel_memory = KERNEL_MEMORY.lock();
+        assert!(kernel_memory.is_none(), "kernel memory already initialized");
+        *kernel_memory = Some(KernelMemory {
+            mapper,
+            frame_allocator,
+        });
+    });
+}
+
+/// KERNEL_RANGES の L4 エントリのうち空いているものに、空のレベル3テーブルを割り当てる
+fn populate_kernel_l4_entries(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    let physical_memory_offset = mapper.phys_offset();
+    let level_4_table = mapper.level_4_table();
+
+    for &(start, size) in KERNEL_RANGES.iter() {
+        let first = u16::from(VirtAddr::new(start).p4_index());
+        let last = u16::from(VirtAddr::new(start + size - 1).p4_index());
+        for index in first..=last {
+            let entry = &mut level_4_table[usize::from(index)];
+            if !entry.is_unused() {
+                continue;
+            }
+
+            let frame: PhysFrame = frame_allocator
+                .allocate_frame()
+                .expect("no frame for a kernel page table");
+            let table: *mut PageTable =
+                (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+            unsafe { (*table).zero() };
+            entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+}
+
+/// 登録済みのページテーブルとフレームアロケータを使って処理を行う。
+///
+/// まだ登録されていなければ None を返す。
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| KERNEL_MEMORY.lock().as_mut().map(f))
+}
+
+/// `with_kernel_memory` と同様だが、ロックが取得できなければ待たずに None を返す。
+///
+/// ロックを保持したままヒープから割り当てる処理があってもデッドロックしないよう、
+/// アロケータの内部から呼ぶ場合はこちらを使う。
+pub fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        KERNEL_MEMORY.try_lock()?.as_mut().map(f)
+    })
+}
+
+/// KERNEL_MEMORY のロックが保持されているかを返す
+///
+/// ロックを保持したままヒープから割り当てていないかを、アロケータが確かめるのに使う。
+pub fn kernel_memory_locked() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| KERNEL_MEMORY.try_lock().is_none())
+}
+
 /// 新しい OffsetPageTable を初期化する。
 ///
 /// この関数は、全物理メモリが、physical_memory_offset から始まる仮想アドレス空間上に
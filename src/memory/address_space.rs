@@ -0,0 +1,259 @@
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    cow::{self, CowError},
+    walk::PageTableWalker,
+    with_kernel_memory,
+};
+
+const ENTRY_COUNT: usize = 512;
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// カーネルと共有している L4 エントリの範囲は変更できない
+    SharedWithKernel,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    Cow(CowError),
+}
+
+/// 独自のレベル4テーブルを持つアドレス空間
+///
+/// 作成時にカーネルのレベル4テーブルで使用中のエントリ (上位半分を含む) をコピーするので、
+/// カーネルのコード、ヒープ、物理メモリのマッピングはどのアドレス空間からも見える。
+/// ヒープ、vma、カーネルスタックの範囲の L4 エントリは `init_kernel_memory` で埋めてあるので、
+/// 作成後にそこへマップしたページも見える。それ以外の空いている L4 エントリにカーネルが後からマップしたものは見えない。
+/// ブートローダはカーネルを下位半分に置くため、上位半分だけでなく使用中のエントリをすべて共有する。
+/// 共有したエントリ以外の範囲は、このアドレス空間だけのページテーブルで管理される。
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    /// カーネルと共有している L4 エントリのビットマップ
+    shared: [u64; ENTRY_COUNT / 64],
+}
+
+impl AddressSpace {
+    /// 新しいレベル4テーブルを確保してアドレス空間を作る
+    ///
+    /// カーネルのページテーブルが登録されていないか、フレームが足りなければ None を返す。
+    pub fn new() -> Option<Self> {
+        with_kernel_memory(|memory| {
+            let physical_memory_offset = memory.mapper.phys_offset();
+            let level_4_frame = memory.frame_allocator.allocate_frame()?;
+
+            let mut address_space = AddressSpace {
+                level_4_frame,
+                physical_memory_offset,
+                shared: [0; ENTRY_COUNT / 64],
+            };
+
+            let kernel_table = memory.mapper.level_4_table();
+            let table = unsafe { address_space.table_at(level_4_frame.start_address()) };
+            table.zero();
+            for (index, entry) in kernel_table.iter().enumerate() {
+                if !entry.is_unused() {
+                    table[index] = entry.clone();
+                    address_space.shared[index / 64] |= 1 << (index % 64);
+                }
+            }
+
+            Some(address_space)
+        })?
+    }
+
+    /// レベル4テーブルのフレームを返す
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// このアドレス空間が現在 CR3 に設定されているかを返す
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// CR3 をこのアドレス空間に切り替え、切り替える前の値を返す
+    ///
+    /// 戻すときは返された値を `Cr3::write` に渡す。
+    /// 呼び出し元は、実行中のコードとスタックがこのアドレス空間でもマップされていることを保証しなければならない。
+    pub unsafe fn switch_to(&self) -> (PhysFrame, Cr3Flags) {
+        let previous = Cr3::read();
+        Cr3::write(self.level_4_frame, previous.1);
+        previous
+    }
+
+    /// page を frame にマップする。途中のページテーブルはカーネルのフレームアロケータから確保する
+    pub fn map(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_not_shared(page.start_address())?;
+
+        with_kernel_memory(|memory| {
+            let mut mapper = unsafe { self.mapper() };
+            unsafe { mapper.map_to(page, frame, flags, &mut memory.frame_allocator) }
+                .map(|flush| {
+                    // 別のアドレス空間がアクティブなら TLB に古いエントリは残っていない
+                    if self.is_active() {
+                        flush.flush();
+                    } else {
+                        flush.ignore();
+                    }
+                })
+                .map_err(AddressSpaceError::Map)
+        })
+        .expect("kernel memory not initialized")
+    }
+
+    /// page のマップを解除し、マップされていたフレームを返す。フレームの解放は呼び出し元が行う
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<PhysFrame, AddressSpaceError> {
+        self.check_not_shared(page.start_address())?;
+
+        let active = self.is_active();
+        let mut mapper = unsafe { self.mapper() };
+        let (frame, flush) = mapper.unmap(page).map_err(AddressSpaceError::Unmap)?;
+        if active {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+        Ok(frame)
+    }
+
+    /// page にマップされているフレームを、target の同じアドレスにもコピーオンライトでマップする
+    ///
+    /// 書き込み可能なページなら、どちらかのアドレス空間で書き込んだときにフレームがコピーされる。
+    /// unmap で返されたフレームを `deallocate_frame` すれば、参照が 1 つ減る。
+    pub fn share_cow(
+        &mut self,
+        page: Page<Size4KiB>,
+        target: &mut AddressSpace,
+    ) -> Result<(), AddressSpaceError> {
+        self.check_not_shared(page.start_address())?;
+        target.check_not_shared(page.start_address())?;
+
+        with_kernel_memory(|memory| {
+            let mut mapper = unsafe { self.mapper() };
+            let (frame, flags) = cow::mark_cow(&mut mapper, page, &memory.frame_allocator)?;
+
+            let target_active = target.is_active();
+            let mut target_mapper = unsafe { target.mapper() };
+            let flush = cow::map_shared(
+                &mut target_mapper,
+                page,
+                frame,
+                flags,
+                &mut memory.frame_allocator,
+            )?;
+            if target_active {
+                flush.flush();
+            } else {
+                flush.ignore();
+            }
+            Ok(())
+        })
+        .expect("kernel memory not initialized")
+        .map_err(AddressSpaceError::Cow)
+    }
+
+    /// このアドレス空間のページテーブルで addr を物理アドレスに変換する
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        // 変換では書き込まないので、一時的に可変参照を作っても問題ない
+        let mapper = unsafe { self.mapper() };
+        mapper.translate_addr(addr)
+    }
+
+    /// このアドレス空間のページテーブルを辿る Walker を返す
+    pub fn walker(&self) -> PageTableWalker<'_> {
+        unsafe { PageTableWalker::new(self.level_4_frame, self.physical_memory_offset) }
+    }
+
+    /// L4 の index 番目のエントリをカーネルと共有しているかを返す
+    fn is_shared(&self, index: usize) -> bool {
+        self.shared[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn check_not_shared(&self, addr: VirtAddr) -> Result<(), AddressSpaceError> {
+        if self.is_shared(usize::from(addr.p4_index())) {
+            Err(AddressSpaceError::SharedWithKernel)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 物理アドレスにあるページテーブルへの参照を返す
+    unsafe fn table_at(&self, addr: PhysAddr) -> &'static mut PageTable {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        &mut *virt.as_mut_ptr()
+    }
+
+    /// このアドレス空間のページテーブルを操作する OffsetPageTable を作る
+    ///
+    /// 呼び出し元は、返された値を使っている間に他の参照からテーブルを変更しないことを保証しなければならない。
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        let level_4_table = self.table_at(self.level_4_frame.start_address());
+        OffsetPageTable::new(level_4_table, self.physical_memory_offset)
+    }
+
+    /// level のテーブルから下位のページテーブルのフレームを再帰的に解放する
+    unsafe fn free_table(
+        &self,
+        table: &PageTable,
+        level: u8,
+        frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        for entry in table.iter() {
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                continue;
+            }
+            let frame = match entry.frame() {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            if level > 2 {
+                self.free_table(
+                    self.table_at(frame.start_address()),
+                    level - 1,
+                    frame_allocator,
+                );
+            }
+            frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+/// レベル4テーブルと、共有していない範囲のページテーブルのフレームを解放する
+///
+/// マップしたフレーム自体は解放しない。
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        with_kernel_memory(|memory| unsafe {
+            let level_4_table = self.table_at(self.level_4_frame.start_address());
+            for (index, entry) in level_4_table.iter().enumerate() {
+                if self.is_shared(index) || entry.is_unused() {
+                    continue;
+                }
+                if let Ok(frame) = entry.frame() {
+                    self.free_table(
+                        self.table_at(frame.start_address()),
+                        3,
+                        &mut memory.frame_allocator,
+                    );
+                    memory.frame_allocator.deallocate_frame(frame);
+                }
+            }
+            memory.frame_allocator.deallocate_frame(self.level_4_frame);
+        });
+    }
+}
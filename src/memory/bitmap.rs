@@ -0,0 +1,336 @@
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+
+/// usable な物理フレームをビットマップで管理するフレームアロケータ
+///
+/// 1 ビットが 1 フレームに対応し、ビットが立っているフレームは使用中 (または usable でない) とみなす。
+/// 4KiB フレームごとに参照カウントも持ち、`share_frame` で共有したフレームは
+/// 最後の参照が `deallocate_frame` されたときに解放される。
+/// usable でないフレームとビットマップ自身が使うフレームは別のビットマップで予約済みとして記録し、
+/// それらを解放しようとするとパニックする。
+/// ビットマップと参照カウントは最初に見つかった十分な大きさの usable 領域の先頭に置かれる。
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    /// 解放してはならないフレームのビットマップ
+    reserved: &'static mut [u64],
+    /// フレームごとの、確保したときの 1 つを除いた参照の数
+    extra_refs: &'static mut [u16],
+    /// ビットマップの先頭ビットに対応する物理フレーム番号
+    base_frame: u64,
+    /// usable なフレームの総数
+    usable_frames: usize,
+    /// 空きフレームの数
+    free_frames: usize,
+    /// このインデックスより前のワードはすべて使用中であることが保証される
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// 渡されたメモリマップからビットマップを構築してフレームアロケータを作る
+    ///
+    /// 呼び出し元は渡されたメモリマップが有効であり、`USABLE` なフレームが実際に未使用であることを保証しなければならない。
+    /// また、全物理メモリが physical_memory_offset から始まる仮想アドレス空間上にマップされている必要がある。
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // usable なフレームが存在する範囲だけをビットマップで覆う
+        let first_frame = usable_regions()
+            .map(|r| r.range.start_addr() / FRAME_SIZE)
+            .min()
+            .expect("no usable memory region");
+        let last_frame = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap();
+
+        let frame_count = (last_frame - first_frame) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_size = (word_count * core::mem::size_of::<u64>()) as u64;
+        let refs_size = (word_count * BITS_PER_WORD * core::mem::size_of::<u16>()) as u64;
+        let metadata_size = bitmap_size * 2 + refs_size;
+
+        // ビットマップと参照カウントを格納できる usable 領域を探す
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= metadata_size)
+            .expect("no usable memory region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        let reserved = slice::from_raw_parts_mut(bitmap_ptr.add(word_count), word_count);
+        let refs_ptr = bitmap_ptr.add(word_count * 2) as *mut u16;
+        let extra_refs = slice::from_raw_parts_mut(refs_ptr, word_count * BITS_PER_WORD);
+
+        // 一旦すべてを使用中にしてから、usable な領域だけを空きにする
+        for word in bitmap.iter_mut().chain(reserved.iter_mut()) {
+            *word = u64::MAX;
+        }
+        for refs in extra_refs.iter_mut() {
+            *refs = 0;
+        }
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            reserved,
+            extra_refs,
+            base_frame: first_frame,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for region in usable_regions() {
+            let start = region.range.start_addr() / FRAME_SIZE;
+            let end = region.range.end_addr() / FRAME_SIZE;
+            for frame_number in start..end {
+                let index = allocator.index_of(frame_number);
+                allocator.set_free(index);
+                allocator.reserved[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+            }
+            allocator.usable_frames += (end - start) as usize;
+        }
+
+        // ビットマップと参照カウントが置かれたフレームは使用中かつ予約済みにしておく
+        let bitmap_frames = (metadata_size + FRAME_SIZE - 1) / FRAME_SIZE;
+        for i in 0..bitmap_frames {
+            let index = allocator.index_of(bitmap_start / FRAME_SIZE + i);
+            allocator.set_used(index);
+            allocator.reserved[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        }
+
+        allocator
+    }
+
+    /// 空きフレームの数を返す
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// 使用中のフレームの数を返す (ビットマップ自身が使うフレームを含む)
+    pub fn used_frames(&self) -> usize {
+        self.usable_frames - self.free_frames
+    }
+
+    /// 管理対象の usable なフレームの総数を返す
+    pub fn total_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// frame の参照の数を返す。空いているフレームや管理対象外のフレームでは 0
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        match self.managed_index(frame) {
+            Some(index) if self.is_used(index) => 1 + usize::from(self.extra_refs[index]),
+            _ => 0,
+        }
+    }
+
+    /// 使用中の frame の参照を 1 つ増やす
+    ///
+    /// 増やした参照は `deallocate_frame` で 1 つずつ戻し、最後の参照が戻されたときにフレームが解放される。
+    /// このアロケータが確保したフレームでなければパニックする。
+    pub fn share_frame(&mut self, frame: PhysFrame) {
+        let index = self
+            .managed_index(frame)
+            .filter(|&index| self.is_used(index))
+            .unwrap_or_else(|| panic!("sharing a frame that is not allocated: {:?}", frame));
+        self.extra_refs[index] = self.extra_refs[index]
+            .checked_add(1)
+            .expect("too many references to a frame");
+    }
+
+    /// count 個 (2 のべき乗) の連続した空きフレームを、count フレームの境界に揃えて確保する
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let count = count as u64;
+        let total = (self.bitmap.len() * BITS_PER_WORD) as u64;
+        let mut frame_number = (self.base_frame + count - 1) & !(count - 1);
+
+        while frame_number + count <= self.base_frame + total {
+            let index = self.index_of(frame_number);
+            if self.is_run_free(index, count as usize) {
+                for i in index..index + count as usize {
+                    self.set_used(i);
+                }
+                return Some(self.frame_at(index));
+            }
+            frame_number += count;
+        }
+
+        None
+    }
+
+    /// allocate_contiguous で確保したフレームを解放する
+    fn deallocate_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let index = self
+            .managed_index(frame)
+            .filter(|&index| index + count <= self.bitmap.len() * BITS_PER_WORD)
+            .unwrap_or_else(|| {
+                panic!(
+                    "deallocating frame outside of the managed range: {:?}",
+                    frame
+                )
+            });
+        // 途中で止まって一部だけ解放されないよう、先にすべて調べる
+        for i in index..index + count {
+            assert!(
+                !self.is_reserved(i),
+                "deallocating reserved frame {:?}",
+                self.frame_at(i)
+            );
+            assert!(self.is_used(i), "double free of frame {:?}", frame);
+        }
+        for i in index..index + count {
+            self.set_free(i);
+        }
+    }
+
+    fn is_run_free(&self, index: usize, count: usize) -> bool {
+        let end = index + count;
+        let mut i = index;
+        while i < end {
+            // ワード全体が範囲に含まれるときはまとめて調べる
+            if i % BITS_PER_WORD == 0 && end - i >= BITS_PER_WORD {
+                if self.bitmap[i / BITS_PER_WORD] != 0 {
+                    return false;
+                }
+                i += BITS_PER_WORD;
+            } else {
+                if self.is_used(i) {
+                    return false;
+                }
+                i += 1;
+            }
+        }
+        true
+    }
+
+    /// frame が管理対象の範囲にあれば、そのインデックスを返す
+    fn managed_index(&self, frame: PhysFrame) -> Option<usize> {
+        let frame_number = frame.start_address().as_u64() / FRAME_SIZE;
+        let total = (self.bitmap.len() * BITS_PER_WORD) as u64;
+        if frame_number >= self.base_frame && frame_number < self.base_frame + total {
+            Some(self.index_of(frame_number))
+        } else {
+            None
+        }
+    }
+
+    fn index_of(&self, frame_number: u64) -> usize {
+        (frame_number - self.base_frame) as usize
+    }
+
+    fn frame_at(&self, index: usize) -> PhysFrame {
+        let addr = (self.base_frame + index as u64) * FRAME_SIZE;
+        PhysFrame::containing_address(PhysAddr::new(addr))
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    /// usable でないか、ビットマップ自身が使っているフレームかを返す
+    fn is_reserved(&self, index: usize) -> bool {
+        self.reserved[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        debug_assert!(!self.is_used(index));
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, index: usize) {
+        debug_assert!(self.is_used(index));
+        let word = index / BITS_PER_WORD;
+        self.bitmap[word] &= !(1 << (index % BITS_PER_WORD));
+        self.free_frames += 1;
+        self.next_word = self.next_word.min(word);
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        // next_word より前はすべて使用中なので、そこから空きビットを探す
+        for word in self.next_word..self.bitmap.len() {
+            let bits = self.bitmap[word];
+            if bits != u64::MAX {
+                let index = word * BITS_PER_WORD + (!bits).trailing_zeros() as usize;
+                self.next_word = word;
+                self.set_used(index);
+                return Some(self.frame_at(index));
+            }
+        }
+
+        self.next_word = self.bitmap.len();
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = self.managed_index(frame).unwrap_or_else(|| {
+            panic!(
+                "deallocating frame outside of the managed range: {:?}",
+                frame
+            )
+        });
+        assert!(
+            !self.is_reserved(index),
+            "deallocating reserved frame {:?}",
+            frame
+        );
+        assert!(self.is_used(index), "double free of frame {:?}", frame);
+
+        // 共有されていれば参照を減らすだけにする
+        if self.extra_refs[index] > 0 {
+            self.extra_refs[index] -= 1;
+            return;
+        }
+        self.set_free(index);
+    }
+}
+
+/// ページサイズ S の一フレームに含まれる 4KiB フレームの数
+fn frames_per<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE) as usize
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_contiguous(frames_per::<Size2MiB>())?;
+        Some(PhysFrame::from_start_address(frame.start_address()).unwrap())
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let frame = self.allocate_contiguous(frames_per::<Size1GiB>())?;
+        Some(PhysFrame::from_start_address(frame.start_address()).unwrap())
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(frame, frames_per::<Size2MiB>());
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(frame, frames_per::<Size1GiB>());
+    }
+}
@@ -0,0 +1,334 @@
+use core::{mem, slice};
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+
+/// 扱う最大のオーダー。2^18 フレーム = 1GiB
+pub const MAX_ORDER: usize = 18;
+
+/// 空きブロックの先頭に書き込まれるリストノード
+struct FreeBlock {
+    prev: Option<PhysAddr>,
+    next: Option<PhysAddr>,
+}
+
+/// バディシステムによる物理フレームアロケータ
+///
+/// 2^order 個の連続したフレームをブロックとして扱い、オーダーごとの双方向のフリーリストで管理する。
+/// フリーリストのノードは空きブロック自身に書き込まれる。
+/// どのブロックが空いているかはオーダーごとのビットマップでも管理しているので、バディが空いているかは
+/// リストを辿らずにわかり、二重解放も検出できる。ビットマップは初期化時に usable な領域の先頭に置く。
+pub struct BuddyFrameAllocator {
+    free_lists: [Option<PhysAddr>; MAX_ORDER + 1],
+    /// オーダーごとの空きブロックの数
+    free_counts: [usize; MAX_ORDER + 1],
+    physical_memory_offset: VirtAddr,
+    /// 管理する物理アドレス範囲の先頭。最大のブロックの境界に揃えてある
+    base: u64,
+    /// 管理する物理アドレス範囲の終端
+    end: u64,
+    /// 全オーダーのビットマップ。ビットが立っているブロックが空いている
+    bitmaps: &'static mut [u64],
+    /// オーダーごとのビットマップの先頭の位置 (語単位)
+    bitmap_offsets: [usize; MAX_ORDER + 1],
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// 渡されたメモリマップの usable な領域をすべてフリーリストに登録してアロケータを作る
+    ///
+    /// 呼び出し元は渡されたメモリマップが有効であり、`USABLE` なフレームが実際に未使用であることを保証しなければならない。
+    /// また、全物理メモリが physical_memory_offset から始まる仮想アドレス空間上にマップされている必要がある。
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // usable なフレームが存在する範囲だけをビットマップで覆う
+        let start = usable_regions()
+            .map(|r| r.range.start_addr())
+            .min()
+            .expect("no usable memory region");
+        let end = usable_regions().map(|r| r.range.end_addr()).max().unwrap();
+        let base = start & !(block_size(MAX_ORDER) - 1);
+
+        let mut bitmap_offsets = [0; MAX_ORDER + 1];
+        let mut word_count = 0;
+        for (order, offset) in bitmap_offsets.iter_mut().enumerate() {
+            *offset = word_count;
+            let blocks = ((end - base + block_size(order) - 1) / block_size(order)) as usize;
+            word_count += (blocks + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        }
+        let bitmap_size = (word_count * mem::size_of::<u64>()) as u64;
+
+        // ビットマップを格納できる usable 領域を探し、その先頭に置く
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .expect("no usable memory region large enough for the buddy bitmaps");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_end = (bitmap_start + bitmap_size + FRAME_SIZE - 1) & !(FRAME_SIZE - 1);
+        let bitmap_ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmaps = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        for word in bitmaps.iter_mut() {
+            *word = 0;
+        }
+
+        let mut allocator = BuddyFrameAllocator {
+            free_lists: [None; MAX_ORDER + 1],
+            free_counts: [0; MAX_ORDER + 1],
+            physical_memory_offset,
+            base,
+            end,
+            bitmaps,
+            bitmap_offsets,
+            free_frames: 0,
+        };
+
+        for region in usable_regions() {
+            let mut addr = region.range.start_addr();
+            // ビットマップが置かれたフレームは登録しない
+            if addr == bitmap_start {
+                addr = bitmap_end;
+            }
+            let end = region.range.end_addr();
+
+            // 領域をアラインメントを満たす最大のブロックに切り分けて登録する
+            while addr < end {
+                let mut order = MAX_ORDER;
+                while order > 0 && (addr % block_size(order) != 0 || addr + block_size(order) > end)
+                {
+                    order -= 1;
+                }
+
+                allocator.deallocate(PhysFrame::containing_address(PhysAddr::new(addr)), order);
+                addr += block_size(order);
+            }
+        }
+
+        allocator
+    }
+
+    /// 2^order 個の物理的に連続したフレームを割り当て、その先頭フレームを返す
+    ///
+    /// 返されるブロックは 2^order フレームの境界にアラインされている。
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        // 要求以上のオーダーで空いている最小のブロックを探す
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o].is_some())?;
+        let addr = self.pop(current).unwrap();
+
+        // 要求オーダーになるまで分割し、上半分をフリーリストに戻す
+        while current > order {
+            current -= 1;
+            unsafe { self.push(current, addr + block_size(current)) };
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(addr))
+    }
+
+    /// `allocate` で得たブロックを解放する
+    ///
+    /// バディが空いていれば結合し、より大きなブロックとしてフリーリストに戻す。
+    /// 管理する範囲の外のブロックや、空いているブロックと重なるブロックを渡すとパニックする。
+    /// 呼び出し元は、frame と order が割り当て時と同じであり、ブロックが未使用であることを保証しなければならない。
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        assert!(order <= MAX_ORDER, "invalid block order: {}", order);
+
+        let mut addr = frame.start_address();
+        assert!(
+            addr.is_aligned(block_size(order)),
+            "block {:?} is not aligned to order {}",
+            addr,
+            order
+        );
+        assert!(
+            addr.as_u64() >= self.base && addr.as_u64() + block_size(order) <= self.end,
+            "block {:?} of order {} is outside of managed memory",
+            addr,
+            order
+        );
+        if let Some(free_order) = self.overlapping_free_order(addr, order) {
+            panic!(
+                "double free of block {:?} of order {} (overlaps a free block of order {})",
+                addr, order, free_order
+            );
+        }
+
+        self.free_frames += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_size(order));
+            if !self.is_free(order, buddy) {
+                break;
+            }
+
+            // バディと結合して一つ上のオーダーへ
+            self.remove(order, buddy);
+            addr = PhysAddr::new(addr.as_u64().min(buddy.as_u64()));
+            order += 1;
+        }
+
+        self.push(order, addr);
+    }
+
+    /// 空きフレームの数を返す
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// 指定したオーダーのフリーリストにあるブロックの数を返す
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_counts[order]
+    }
+
+    /// size バイトを格納できる最小のオーダーを返す
+    pub fn order_for_size(size: usize) -> Option<usize> {
+        (0..=MAX_ORDER).find(|&order| block_size(order) >= size as u64)
+    }
+
+    /// addr から始まる order のブロックと重なる空きブロックがあれば、そのオーダーを返す
+    ///
+    /// addr を含むより大きなブロックと、addr から始まるより小さなブロックを調べる。
+    fn overlapping_free_order(&self, addr: PhysAddr, order: usize) -> Option<usize> {
+        let containing = (order..=MAX_ORDER).find(|&o| {
+            let start = PhysAddr::new(addr.as_u64() & !(block_size(o) - 1));
+            self.is_free(o, start)
+        });
+        containing.or_else(|| (0..order).find(|&o| self.is_free(o, addr)))
+    }
+
+    /// addr から始まる order のブロックが空いているかを返す
+    fn is_free(&self, order: usize, addr: PhysAddr) -> bool {
+        match self.bit_position(order, addr) {
+            Some((word, mask)) => self.bitmaps[word] & mask != 0,
+            None => false,
+        }
+    }
+
+    /// ビットマップ上の位置。管理する範囲の外なら None を返す
+    fn bit_position(&self, order: usize, addr: PhysAddr) -> Option<(usize, u64)> {
+        let addr = addr.as_u64();
+        if addr < self.base || addr + block_size(order) > self.end {
+            return None;
+        }
+        let index = ((addr - self.base) / block_size(order)) as usize;
+        Some((
+            self.bitmap_offsets[order] + index / BITS_PER_WORD,
+            1 << (index % BITS_PER_WORD),
+        ))
+    }
+
+    fn set_free(&mut self, order: usize, addr: PhysAddr, free: bool) {
+        let (word, mask) = self
+            .bit_position(order, addr)
+            .expect("block is outside of managed memory");
+        if free {
+            self.bitmaps[word] |= mask;
+        } else {
+            self.bitmaps[word] &= !mask;
+        }
+    }
+
+    fn node(&self, addr: PhysAddr) -> *mut FreeBlock {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        virt.as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, order: usize, addr: PhysAddr) {
+        let next = self.free_lists[order].take();
+        self.node(addr).write(FreeBlock { prev: None, next });
+        if let Some(next) = next {
+            (*self.node(next)).prev = Some(addr);
+        }
+        self.free_lists[order] = Some(addr);
+
+        self.set_free(order, addr, true);
+        self.free_counts[order] += 1;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<PhysAddr> {
+        let addr = self.free_lists[order]?;
+        self.remove(order, addr);
+        Some(addr)
+    }
+
+    /// 空いているブロックをフリーリストから取り除く
+    fn remove(&mut self, order: usize, addr: PhysAddr) {
+        let FreeBlock { prev, next } = unsafe { self.node(addr).read() };
+        match prev {
+            Some(prev) => unsafe { (*self.node(prev)).next = next },
+            None => self.free_lists[order] = next,
+        }
+        if let Some(next) = next {
+            unsafe { (*self.node(next)).prev = prev };
+        }
+
+        self.set_free(order, addr, false);
+        self.free_counts[order] -= 1;
+    }
+}
+
+/// 与えられたオーダーのブロックのバイト数を返す
+fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// ページサイズ S に対応するブロックのオーダーを返す
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(order_of::<Size4KiB>())
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate(order_of::<Size2MiB>())?;
+        Some(PhysFrame::from_start_address(frame.start_address()).unwrap())
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let frame = self.allocate(order_of::<Size1GiB>())?;
+        Some(PhysFrame::from_start_address(frame.start_address()).unwrap())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame, order_of::<Size4KiB>());
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate(frame, order_of::<Size2MiB>());
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        let frame = PhysFrame::containing_address(frame.start_address());
+        self.deallocate(frame, order_of::<Size1GiB>());
+    }
+}
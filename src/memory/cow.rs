@@ -0,0 +1,174 @@
+use x86_64::{
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, MappedFrame, MapperFlush, TranslateResult},
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+            PageTableFlags, PhysFrame, Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use super::{bitmap::BitmapFrameAllocator, try_with_kernel_memory, KernelMemory};
+
+/// コピーオンライトで共有しているページの印。OS が自由に使えるビットを使う
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    /// 共有元のページが 4KiB のページでマップされていない
+    NotMapped,
+    /// 共有元のフレームがフレームアロケータで確保したものではない
+    UnmanagedFrame,
+    Map(MapToError<Size4KiB>),
+}
+
+impl KernelMemory {
+    /// src にマップされているフレームを、dst にもコピーオンライトでマップする
+    ///
+    /// 書き込み可能なページなら src も読み取り専用になり、どちらかに書き込んだときにフレームがコピーされる。
+    /// dst のマップを解除するときはフレームを `deallocate_frame` すれば、参照が 1 つ減る。
+    pub fn share_cow(&mut self, src: Page, dst: Page) -> Result<(), CowError> {
+        let (frame, flags) = mark_cow(&mut self.mapper, src, &self.frame_allocator)?;
+        map_shared(
+            &mut self.mapper,
+            dst,
+            frame,
+            flags,
+            &mut self.frame_allocator,
+        )?
+        .flush();
+        Ok(())
+    }
+}
+
+/// mapper の page をコピーオンライトにし、マップされているフレームと共有先で使うフラグを返す
+///
+/// 読み取り専用のページはコピーする必要がないので、フラグを変えずにそのまま共有する。
+pub(super) fn mark_cow(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame_allocator: &BitmapFrameAllocator,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (
+            frame,
+            flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY,
+        ),
+        _ => return Err(CowError::NotMapped),
+    };
+    if frame_allocator.ref_count(frame) == 0 {
+        return Err(CowError::UnmanagedFrame);
+    }
+    if !flags.contains(PageTableFlags::WRITABLE) {
+        return Ok((frame, flags));
+    }
+
+    let cow_flags = flags - PageTableFlags::WRITABLE | COW;
+    unsafe {
+        mapper
+            .update_flags(page, cow_flags)
+            .expect("failed to update flags")
+            .flush()
+    };
+    Ok((frame, cow_flags))
+}
+
+/// frame の参照を増やして mapper の page にマップする
+pub(super) fn map_shared(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<MapperFlush<Size4KiB>, CowError> {
+    frame_allocator.share_frame(frame);
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }.map_err(|err| {
+        // 増やした参照を戻す
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        CowError::Map(err)
+    })
+}
+
+/// ページフォルトハンドラから呼ばれる。コピーオンライトのページへの書き込みなら解消して true を返す
+///
+/// 割り込みハンドラの中で実行されるので、ヒープから割り当てず、ロックも待たない。
+pub(crate) fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_to_present)
+        || error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+    {
+        return false;
+    }
+
+    let page = Page::containing_address(addr);
+    try_with_kernel_memory(|memory| {
+        // ロックを保持しているので、有効なページテーブルを他から変更されることはない
+        let mut mapper = unsafe { active_mapper(memory.mapper.phys_offset()) };
+        unsafe { break_cow(&mut mapper, page, &mut memory.frame_allocator) }
+    })
+    .unwrap_or(false)
+}
+
+/// page のフレームを必要ならコピーし、書き込み可能にする
+///
+/// 他に参照がなければコピーせずにフラグだけを戻す。
+unsafe fn break_cow(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> bool {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COW) => (frame, flags),
+        _ => return false,
+    };
+    let writable =
+        flags - COW - PageTableFlags::ACCESSED - PageTableFlags::DIRTY | PageTableFlags::WRITABLE;
+
+    if frame_allocator.ref_count(frame) == 1 {
+        mapper
+            .update_flags(page, writable)
+            .expect("failed to update flags")
+            .flush();
+        return true;
+    }
+
+    let new_frame: PhysFrame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    let offset = mapper.phys_offset();
+    let src: *const u8 = (offset + frame.start_address().as_u64()).as_ptr();
+    let dst: *mut u8 = (offset + new_frame.start_address().as_u64()).as_mut_ptr();
+    dst.copy_from_nonoverlapping(src, Size4KiB::SIZE as usize);
+
+    // 途中のページテーブルは残っているので、マップし直しても新たなフレームは要らない
+    mapper.unmap(page).expect("failed to unmap").1.ignore();
+    mapper
+        .map_to(page, new_frame, writable, frame_allocator)
+        .expect("failed to remap")
+        .flush();
+    frame_allocator.deallocate_frame(frame);
+    true
+}
+
+/// CR3 に設定されているページテーブルを操作する OffsetPageTable を作る
+///
+/// 呼び出し元は、返された値を使っている間に他からページテーブルを変更しないことを保証しなければならない。
+unsafe fn active_mapper(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    let (level_4_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_frame.start_address().as_u64();
+    let level_4_table: &'static mut PageTable = &mut *virt.as_mut_ptr();
+    OffsetPageTable::new(level_4_table, physical_memory_offset)
+}
@@ -0,0 +1,168 @@
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::TranslateResult, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags,
+            Size4KiB, Translate,
+        },
+    },
+    VirtAddr,
+};
+
+use super::try_with_kernel_memory;
+
+/// 同時に登録できる領域の数
+const MAX_REGIONS: usize = 32;
+
+/// 仮想アドレスだけを予約し、触れられたページから順にフレームを割り当てる領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DemandRegion {
+    pub start: VirtAddr,
+    pub size: u64,
+    /// ページをマップするときのフラグ。PRESENT は自動で付く
+    pub flags: PageTableFlags,
+}
+
+impl DemandRegion {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    fn overlaps(&self, other: &DemandRegion) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end()),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReserveError {
+    /// 開始アドレスかサイズがページ境界に揃っていない
+    Unaligned,
+    /// 登録済みの領域と重なっている
+    Overlap,
+    /// 登録できる領域の数を超えた
+    TooManyRegions,
+}
+
+// ページフォルトハンドラから参照するので、割り当てを伴わない固定長の配列で持つ
+static REGIONS: Mutex<[Option<DemandRegion>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// start から size バイトの仮想アドレス範囲を予約する
+///
+/// この時点ではページはマップされず、最初にアクセスされたときにページフォルトハンドラがフレームを割り当てる。
+/// 割り当てたフレームはゼロで埋められる。
+pub fn reserve(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), ReserveError> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 || size == 0 {
+        return Err(ReserveError::Unaligned);
+    }
+    let region = DemandRegion {
+        start,
+        size,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        if regions
+            .iter()
+            .flatten()
+            .any(|other| other.overlaps(&region))
+        {
+            return Err(ReserveError::Overlap);
+        }
+
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ReserveError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    })
+}
+
+/// start から始まる領域の予約を解除し、マップ済みのページとそのフレームを解放する
+///
+/// 呼び出し元は、領域内のメモリへの参照が残っていないことを保証しなければならない。
+pub unsafe fn release(start: VirtAddr) -> Option<DemandRegion> {
+    let region = interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+        let slot = regions
+            .iter_mut()
+            .find(|slot| matches!(slot, Some(region) if region.start == start))?;
+        slot.take()
+    })?;
+
+    super::with_kernel_memory(|memory| {
+        for page in region.pages() {
+            if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+                flush.flush();
+                memory.frame_allocator.deallocate_frame(frame);
+            }
+        }
+    });
+
+    Some(region)
+}
+
+/// addr を含む予約済みの領域を返す
+pub fn region_containing(addr: VirtAddr) -> Option<DemandRegion> {
+    interrupts::without_interrupts(|| {
+        REGIONS
+            .lock()
+            .iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied()
+    })
+}
+
+/// ページフォルトハンドラから呼ばれる。フォルトを解消できたら true を返す
+///
+/// 割り込みハンドラの中で実行されるので、割り当てを行わず、ロックも待たない。
+pub(crate) fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // すでにマップされているページへの不正なアクセスは対象外
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+
+    let region = match REGIONS.try_lock() {
+        Some(regions) => regions
+            .iter()
+            .flatten()
+            .find(|region| region.contains(addr))
+            .copied(),
+        None => None,
+    };
+    let region = match region {
+        Some(region) => region,
+        None => return false,
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return false;
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    try_with_kernel_memory(|memory| {
+        // 他の CPU やネストしたフォルトがすでにマップしていれば何もしない
+        if let TranslateResult::Mapped { .. } = memory.mapper.translate(page.start_address()) {
+            return true;
+        }
+
+        memory.map_new_page(page, region.flags).is_ok()
+    })
+    .unwrap_or(false)
+}
@@ -0,0 +1,185 @@
+use core::arch::x86_64::__cpuid;
+
+use lazy_static::lazy_static;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{bitmap::BitmapFrameAllocator, KernelMemory};
+
+lazy_static! {
+    // 仮想マシンでは CPUID が重いので一度だけ調べる
+    static ref SUPPORTS_1GIB_PAGES: bool = {
+        let max_extended_leaf = __cpuid(0x8000_0000).eax;
+        max_extended_leaf >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+    };
+}
+
+/// CPU が 1GiB ページに対応しているかを返す
+pub fn supports_1gib_pages() -> bool {
+    *SUPPORTS_1GIB_PAGES
+}
+
+/// virt と phys がともに S の境界に揃っていて、残りが S 以上あるか
+fn fits<S: PageSize>(virt: VirtAddr, phys: PhysAddr, remaining: u64) -> bool {
+    virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE) && remaining >= S::SIZE
+}
+
+impl KernelMemory {
+    /// addr に新しいフレームをマップし、マップしたバイト数を返す
+    ///
+    /// addr の境界と残りのサイズが許せば 1GiB または 2MiB のページを使い、
+    /// 大きなフレームが確保できないときやマップできないときは 4KiB のページで済ませる。
+    /// フレームはゼロで埋められる。
+    pub fn map_new_chunk(
+        &mut self,
+        addr: VirtAddr,
+        remaining: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        let phys = PhysAddr::new(0);
+        if supports_1gib_pages()
+            && fits::<Size1GiB>(addr, phys, remaining)
+            && self
+                .map_new_page::<Size1GiB>(Page::containing_address(addr), flags)
+                .is_ok()
+        {
+            return Ok(Size1GiB::SIZE);
+        }
+        if fits::<Size2MiB>(addr, phys, remaining)
+            && self
+                .map_new_page::<Size2MiB>(Page::containing_address(addr), flags)
+                .is_ok()
+        {
+            return Ok(Size2MiB::SIZE);
+        }
+
+        self.map_new_page::<Size4KiB>(Page::containing_address(addr), flags)?;
+        Ok(Size4KiB::SIZE)
+    }
+
+    /// addr に物理アドレス phys からの範囲をマップし、マップしたバイト数を返す
+    ///
+    /// map_new_chunk と同様に、addr と phys の境界が揃っていれば大きなページを使う。
+    pub fn map_phys_chunk(
+        &mut self,
+        addr: VirtAddr,
+        phys: PhysAddr,
+        remaining: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        if supports_1gib_pages()
+            && fits::<Size1GiB>(addr, phys, remaining)
+            && self.map_page::<Size1GiB>(addr, phys, flags).is_ok()
+        {
+            return Ok(Size1GiB::SIZE);
+        }
+        if fits::<Size2MiB>(addr, phys, remaining)
+            && self.map_page::<Size2MiB>(addr, phys, flags).is_ok()
+        {
+            return Ok(Size2MiB::SIZE);
+        }
+
+        self.map_page::<Size4KiB>(addr, phys, flags)?;
+        Ok(Size4KiB::SIZE)
+    }
+
+    /// start から size バイトの範囲のマップを、ページの大きさを問わず解除する
+    ///
+    /// free_frames が true なら、マップされていたフレームも解放する。
+    /// 呼び出し元は、範囲内のメモリへの参照が残っていないことを保証しなければならない。
+    pub unsafe fn unmap_range(&mut self, start: VirtAddr, size: u64, free_frames: bool) {
+        let end = start + size;
+        let mut addr = start.align_down(Size4KiB::SIZE);
+        while addr < end {
+            let frame = match self.mapper.translate(addr) {
+                TranslateResult::Mapped { frame, .. } => frame,
+                _ => {
+                    addr += Size4KiB::SIZE;
+                    continue;
+                }
+            };
+
+            addr = match frame {
+                MappedFrame::Size4KiB(_) => self.unmap_page::<Size4KiB>(addr, free_frames),
+                MappedFrame::Size2MiB(_) => self.unmap_page::<Size2MiB>(addr, free_frames),
+                MappedFrame::Size1GiB(_) => self.unmap_page::<Size1GiB>(addr, free_frames),
+            };
+        }
+    }
+
+    /// page に大きさ S の新しいフレームをゼロで埋めてからマップする
+    pub(super) fn map_new_page<S: PageSize>(
+        &mut self,
+        page: Page<S>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<S>>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+        BitmapFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+    {
+        let frame: PhysFrame<S> = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        // 書き込み不可でマップすることもあるので、物理メモリのマッピングを通してゼロで埋める
+        let frame_ptr: *mut u8 =
+            (self.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { frame_ptr.write_bytes(0, S::SIZE as usize) };
+
+        match unsafe {
+            self.mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)
+        } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            }
+            Err(err) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                Err(err)
+            }
+        }
+    }
+
+    fn map_page<S: PageSize>(
+        &mut self,
+        addr: VirtAddr,
+        phys: PhysAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<S>>
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+    {
+        let page = Page::<S>::containing_address(addr);
+        let frame = PhysFrame::<S>::containing_address(phys);
+        unsafe {
+            self.mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)?
+                .flush()
+        };
+        Ok(())
+    }
+
+    /// addr を含む大きさ S のページのマップを解除し、次のページの先頭を返す
+    unsafe fn unmap_page<S: PageSize>(&mut self, addr: VirtAddr, free_frame: bool) -> VirtAddr
+    where
+        OffsetPageTable<'static>: Mapper<S>,
+        BitmapFrameAllocator: FrameDeallocator<S>,
+    {
+        let page = Page::<S>::containing_address(addr);
+        if let Ok((frame, flush)) = self.mapper.unmap(page) {
+            flush.flush();
+            if free_frame {
+                self.frame_allocator.deallocate_frame(frame);
+            }
+        }
+        page.start_address() + S::SIZE
+    }
+}
@@ -0,0 +1,284 @@
+use core::{
+    arch::{asm, x86_64::__cpuid},
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use x86_64::{
+    instructions::{interrupts, tlb},
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::Msr,
+    },
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{vma, with_kernel_memory, KernelMemory};
+
+/// map_mmio で確保した範囲の owner
+pub const MMIO_OWNER: &str = "mmio";
+
+const IA32_PAT: u32 = 0x277;
+/// PAT のエントリ PA4 を書き込み結合 (WC) に変更して使う
+const PAT_WRITE_COMBINING_INDEX: u64 = 4;
+const PAT_TYPE_WRITE_COMBINING: u64 = 0x01;
+/// 4KiB ページのエントリでは、bit 7 (HUGE_PAGE と同じ位置) が PAT のインデックスの最上位ビットになる
+///
+/// `Mapper::map_to` は HUGE_PAGE を含むフラグを受け付けないので、マップした後で `update_flags` で立てる。
+const PAGE_PAT: PageTableFlags = PageTableFlags::HUGE_PAGE;
+
+static PAT_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// デバイスメモリのキャッシュの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// キャッシュしない。レジスタ向け
+    Uncached,
+    /// 書き込みをまとめて行う。フレームバッファ向け。PAT がなければ Uncached になる
+    WriteCombining,
+}
+
+#[derive(Debug)]
+pub enum MmioError {
+    /// 長さが 0
+    EmptyRange,
+    /// 空いている仮想アドレス範囲がない
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+/// キャッシュを無効にしてマップしたデバイスメモリ
+///
+/// drop するとマップを解除する。物理フレームはデバイスのものなので解放しない。
+pub struct MmioRegion {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    len: usize,
+    /// 確保した仮想アドレス範囲の先頭
+    area_start: VirtAddr,
+    /// マップしたページ範囲の先頭 (virt をページ境界に切り下げたもの)
+    map_start: VirtAddr,
+    map_size: u64,
+    /// 大きなページを使ってマップしたか
+    huge: bool,
+}
+
+/// 物理アドレス phys から len バイトを `NO_CACHE | WRITE_THROUGH` でマップする
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<MmioRegion, MmioError> {
+    map_mmio_with(phys, len, CachePolicy::Uncached)
+}
+
+/// 物理アドレス phys から len バイトを policy に従ってマップする
+pub fn map_mmio_with(
+    phys: PhysAddr,
+    len: usize,
+    policy: CachePolicy,
+) -> Result<MmioRegion, MmioError> {
+    if len == 0 {
+        return Err(MmioError::EmptyRange);
+    }
+
+    let write_combining = policy == CachePolicy::WriteCombining && init_pat();
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    if !write_combining {
+        flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    }
+
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (len - 1));
+    let offset = phys - first_frame.start_address();
+    let frames_size = last_frame.start_address() - first_frame.start_address() + Size4KiB::SIZE;
+
+    // 書き込み結合は PAT ビットの位置がヒュージページでは異なるので 4KiB ページに限る。
+    // それ以外で 2MiB 以上あれば、仮想アドレスを物理アドレスと同じく 2MiB 境界からずらして確保し、
+    // 大きなページを使えるようにする
+    let huge = !write_combining && frames_size >= Size2MiB::SIZE;
+    let (align, padding) = if huge {
+        (
+            Size2MiB::SIZE,
+            first_frame.start_address().as_u64() % Size2MiB::SIZE,
+        )
+    } else {
+        (Size4KiB::SIZE, 0)
+    };
+    let area = vma::reserve_aligned(frames_size + padding, align, flags, MMIO_OWNER)
+        .ok_or(MmioError::OutOfVirtualSpace)?;
+    let map_start = area.start + padding;
+
+    let result = with_kernel_memory(|memory| {
+        let mut mapped = 0;
+        while mapped < frames_size {
+            let addr = map_start + mapped;
+            let frame_addr = first_frame.start_address() + mapped;
+            let chunk = if huge {
+                memory.map_phys_chunk(addr, frame_addr, frames_size - mapped, flags)
+            } else {
+                map_page(memory, addr, frame_addr, flags, write_combining)
+            };
+            match chunk {
+                Ok(size) => mapped += size,
+                Err(err) => {
+                    unmap(memory, huge, map_start, mapped);
+                    return Err(MmioError::Map(err));
+                }
+            }
+        }
+        Ok(())
+    })
+    .expect("kernel memory not initialized");
+
+    if let Err(err) = result {
+        vma::release(area.start);
+        return Err(err);
+    }
+
+    Ok(MmioRegion {
+        phys,
+        virt: map_start + offset,
+        len,
+        area_start: area.start,
+        map_start,
+        map_size: frames_size,
+        huge,
+    })
+}
+
+impl MmioRegion {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// offset の位置から T を volatile に読む
+    ///
+    /// 範囲外か、T のアラインメントに揃っていなければパニックする。
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// offset の位置に T を volatile に書く
+    ///
+    /// 範囲外か、T のアラインメントに揃っていなければパニックする。
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset
+                .checked_add(mem::size_of::<T>())
+                .map_or(false, |end| end <= self.len),
+            "mmio access out of range: offset {:#x}, len {:#x}",
+            offset,
+            self.len
+        );
+        let ptr = (self.virt + offset).as_mut_ptr::<T>();
+        assert_eq!(
+            ptr as usize % mem::align_of::<T>(),
+            0,
+            "unaligned mmio access"
+        );
+        ptr
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        with_kernel_memory(|memory| unmap(memory, self.huge, self.map_start, self.map_size));
+        vma::release(self.area_start);
+    }
+}
+
+/// 4KiB ページを一つマップする。write_combining なら PAT ビットを立てて PA4 を選ぶ
+fn map_page(
+    memory: &mut KernelMemory,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+    write_combining: bool,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let frame = PhysFrame::containing_address(phys);
+    unsafe {
+        let flush = memory
+            .mapper
+            .map_to(page, frame, flags, &mut memory.frame_allocator)?;
+        if write_combining {
+            // まだアクセスしていないので、PAT ビットを立ててから一度だけ TLB を消す
+            flush.ignore();
+            memory
+                .mapper
+                .update_flags(page, flags | PAGE_PAT)
+                .expect("page was just mapped")
+                .flush();
+        } else {
+            flush.flush();
+        }
+    }
+    Ok(Size4KiB::SIZE)
+}
+
+/// start から size バイトのマップを解除する。フレームは解放しない
+fn unmap(memory: &mut KernelMemory, huge: bool, start: VirtAddr, size: u64) {
+    if huge {
+        unsafe { memory.unmap_range(start, size, false) };
+        return;
+    }
+
+    let first_page = Page::<Size4KiB>::containing_address(start);
+    for i in 0..size / Size4KiB::SIZE {
+        let page = first_page + i;
+        // PAT ビットは HUGE_PAGE と同じ位置にあり、そのままでは unmap がエラーになるので先に落とす
+        if let Ok(flush) = unsafe { memory.mapper.update_flags(page, PageTableFlags::PRESENT) } {
+            flush.ignore();
+        }
+        if let Ok((_, flush)) = memory.mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+}
+
+/// PAT のエントリを書き込み結合に設定する。PAT がなければ false を返す
+fn init_pat() -> bool {
+    let has_pat = __cpuid(1).edx & (1 << 16) != 0;
+    if !has_pat {
+        return false;
+    }
+    if PAT_INITIALIZED.swap(true, Ordering::AcqRel) {
+        return true;
+    }
+
+    // ブートローダは PAT ビットを立てないので、PA4 は使われていない
+    // SDM の手順に従い、キャッシュを無効にしてキャッシュと TLB を消してから書き換える
+    let mut pat = Msr::new(IA32_PAT);
+    interrupts::without_interrupts(|| unsafe {
+        let cr0 = Cr0::read();
+        Cr0::write((cr0 | Cr0Flags::CACHE_DISABLE) - Cr0Flags::NOT_WRITE_THROUGH);
+        wbinvd();
+        tlb::flush_all();
+
+        let shift = PAT_WRITE_COMBINING_INDEX * 8;
+        let value = pat.read() & !(0xff << shift) | (PAT_TYPE_WRITE_COMBINING << shift);
+        pat.write(value);
+
+        wbinvd();
+        tlb::flush_all();
+        Cr0::write(cr0);
+    });
+    true
+}
+
+/// キャッシュの内容をメモリに書き戻して無効にする
+unsafe fn wbinvd() {
+    asm!("wbinvd", options(nostack, preserves_flags));
+}
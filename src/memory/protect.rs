@@ -0,0 +1,232 @@
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
+        Translate,
+    },
+    VirtAddr,
+};
+
+use crate::allocator;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+/// スタックを探すときに rsp から上下に辿る最大のページ数
+const MAX_STACK_PAGES: u64 = 1024;
+
+extern "C" {
+    /// リンカが定義する、メモリ上の ELF ヘッダの先頭
+    static __ehdr_start: ElfHeader;
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ElfHeader {
+    ident: [u8; 16],
+    file_type: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ProgramHeader {
+    segment_type: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+/// EFER.NXE と CR0.WP を有効にする
+///
+/// これ以降、NO_EXECUTE を立てたページは実行できず、カーネルも書き込み不可のページに書き込めない。
+/// NXE を有効にする前に NO_EXECUTE を立てたページにアクセスするとページフォルトになるので、
+/// ページをマップするより前に呼ぶ。
+pub fn enable_nx() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// カーネルのマッピングに W^X を適用する
+///
+/// - ELF のセグメントごとに、.text を読み取り専用、.rodata を読み取り専用かつ実行不可、
+///   .data と .bss を実行不可にする
+/// - ブートローダが用意したスタック、物理メモリのマッピング、ヒープを実行不可にする
+///
+/// 呼び出し元は `enable_nx` を呼んだ後で、書き込みと実行を同時に許すページに依存していないことを保証しなければならない。
+pub unsafe fn enforce_wx(mapper: &mut OffsetPageTable, memory_map: &MemoryMap) {
+    for segment in load_segments() {
+        let writable = segment.flags & PF_W != 0;
+        let executable = segment.flags & PF_X != 0;
+        assert!(
+            !(writable && executable),
+            "kernel segment at {:#x} is writable and executable",
+            segment.vaddr
+        );
+
+        let start = VirtAddr::new(segment.vaddr);
+        update_range(mapper, start, start + segment.memsz, |flags| {
+            let mut flags = flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE;
+            if writable {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !executable {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            flags
+        });
+    }
+
+    let no_execute = |flags: PageTableFlags| flags | PageTableFlags::NO_EXECUTE;
+
+    let (stack_start, stack_end) = current_stack_range(mapper);
+    update_range(mapper, stack_start, stack_end, no_execute);
+
+    let physical_memory_end = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let physical_memory_offset = mapper.phys_offset();
+    update_range(
+        mapper,
+        physical_memory_offset,
+        physical_memory_offset + physical_memory_end,
+        no_execute,
+    );
+
+    // init_heap より後に呼ばれた場合に備えて、マップ済みのヒープにも適用する
+    let heap_start = VirtAddr::new(allocator::HEAP_START as u64);
+    update_range(
+        mapper,
+        heap_start,
+        heap_start + allocator::heap_size(),
+        no_execute,
+    );
+}
+
+/// カーネルの ELF イメージの PT_LOAD セグメントを返す
+fn load_segments() -> impl Iterator<Item = &'static ProgramHeader> {
+    let header = unsafe { &__ehdr_start };
+    let base = header as *const ElfHeader as *const u8;
+    assert_eq!(&header.ident[..4], b"\x7fELF", "ELF header not mapped");
+
+    let program_headers = unsafe { base.add(header.phoff as usize) };
+    (0..usize::from(header.phnum))
+        .map(move |i| unsafe {
+            &*(program_headers.add(i * usize::from(header.phentsize)) as *const ProgramHeader)
+        })
+        .filter(|segment| segment.segment_type == PT_LOAD)
+}
+
+/// rsp を含む、連続してマップされたページの範囲を返す
+fn current_stack_range(mapper: &OffsetPageTable) -> (VirtAddr, VirtAddr) {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+
+    let stack_page = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let is_mapped = |page: Page<Size4KiB>| {
+        matches!(
+            mapper.translate(page.start_address()),
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            }
+        )
+    };
+
+    let mut start = stack_page;
+    for _ in 0..MAX_STACK_PAGES {
+        if !is_mapped(start - 1) {
+            break;
+        }
+        start -= 1;
+    }
+    let mut end = stack_page + 1;
+    for _ in 0..MAX_STACK_PAGES {
+        if !is_mapped(end) {
+            break;
+        }
+        end += 1;
+    }
+
+    (start.start_address(), end.start_address())
+}
+
+/// start から end までにマップされているページのフラグを f で書き換える
+///
+/// ヒュージページはそのままの大きさで書き換える。
+pub(crate) unsafe fn update_range(
+    mapper: &mut OffsetPageTable,
+    start: VirtAddr,
+    end: VirtAddr,
+    f: impl Fn(PageTableFlags) -> PageTableFlags,
+) {
+    let mut addr = start.align_down(Size4KiB::SIZE);
+    while addr < end {
+        let (frame, flags) = match mapper.translate(addr) {
+            TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+            _ => {
+                addr += Size4KiB::SIZE;
+                continue;
+            }
+        };
+
+        let new_flags = f(flags);
+        addr = match frame {
+            MappedFrame::Size4KiB(_) => {
+                let page = Page::<Size4KiB>::containing_address(addr);
+                if new_flags != flags {
+                    mapper
+                        .update_flags(page, new_flags)
+                        .expect("failed to update flags")
+                        .flush();
+                }
+                page.start_address() + Size4KiB::SIZE
+            }
+            MappedFrame::Size2MiB(_) => {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                if new_flags != flags {
+                    mapper
+                        .update_flags(page, new_flags)
+                        .expect("failed to update flags")
+                        .flush();
+                }
+                page.start_address() + Size2MiB::SIZE
+            }
+            MappedFrame::Size1GiB(_) => {
+                let page = Page::<Size1GiB>::containing_address(addr);
+                if new_flags != flags {
+                    mapper
+                        .update_flags(page, new_flags)
+                        .expect("failed to update flags")
+                        .flush();
+                }
+                page.start_address() + Size1GiB::SIZE
+            }
+        };
+    }
+}
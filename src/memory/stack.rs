@@ -0,0 +1,138 @@
+use core::mem;
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{vma::VmaManager, with_kernel_memory};
+
+/// カーネルスタック用の仮想アドレス範囲の先頭。vma の範囲の次の L4 エントリを使う
+pub const KERNEL_STACK_START: u64 = 0x_7080_0000_0000;
+/// カーネルスタック用の仮想アドレス範囲のサイズ
+pub const KERNEL_STACK_REGION_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+const STACK_OWNER: &str = "kernel stack";
+const STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+static KERNEL_STACKS: Mutex<VmaManager> = Mutex::new(VmaManager::new(
+    KERNEL_STACK_START,
+    KERNEL_STACK_REGION_SIZE,
+));
+
+#[derive(Debug)]
+pub enum StackError {
+    /// 空いている仮想アドレス範囲がない
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+/// 下端にマップしないガードページを置いたカーネルスタック
+///
+/// 溢れるとガードページへのアクセスでフォルトになる。drop するとフレームと仮想アドレス範囲を解放する。
+#[derive(Debug)]
+pub struct KernelStack {
+    guard: VirtAddr,
+    top: VirtAddr,
+}
+
+/// size バイト (ページ単位に切り上げる) のカーネルスタックを確保する
+pub fn allocate_stack(size: u64) -> Result<KernelStack, StackError> {
+    let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let area = interrupts::without_interrupts(|| {
+        KERNEL_STACKS
+            .lock()
+            .allocate(size + PAGE_SIZE, STACK_FLAGS, STACK_OWNER)
+    })
+    .ok_or(StackError::OutOfVirtualSpace)?;
+    // 先頭のページはガードページとしてマップしない
+    let bottom = area.start + PAGE_SIZE;
+
+    let result = with_kernel_memory(|memory| {
+        let mut mapped = 0;
+        while mapped < size {
+            let page = Page::containing_address(bottom + mapped);
+            if let Err(err) = memory.map_new_page(page, STACK_FLAGS) {
+                unsafe { memory.unmap_range(bottom, mapped, true) };
+                return Err(StackError::Map(err));
+            }
+            mapped += PAGE_SIZE;
+        }
+        Ok(())
+    })
+    .expect("kernel memory not initialized");
+
+    if let Err(err) = result {
+        interrupts::without_interrupts(|| KERNEL_STACKS.lock().free(area.start));
+        return Err(err);
+    }
+
+    Ok(KernelStack {
+        guard: area.start,
+        top: bottom + size,
+    })
+}
+
+/// addr がカーネルスタックのガードページにあるかを返す
+///
+/// フォルトハンドラから呼ばれるので、ロックが取れなければ false を返す。
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let area = match KERNEL_STACKS.try_lock() {
+        Some(stacks) => stacks.find(addr),
+        None => None,
+    };
+    area.map_or(false, |area| addr < area.start + PAGE_SIZE)
+}
+
+/// fault_addr と、フォルトした時点の rsp からスタックの溢れかどうかを判定する
+///
+/// 二重フォールトでは CR2 が以前のページフォルトのまま残っていることがあるので、
+/// ガードページへのアクセスであることに加えて、rsp がそのすぐ近くにあることも確かめる。
+pub fn is_stack_overflow(fault_addr: VirtAddr, rsp: VirtAddr) -> bool {
+    let distance = if rsp > fault_addr {
+        rsp - fault_addr
+    } else {
+        fault_addr - rsp
+    };
+    distance < PAGE_SIZE && is_guard_page(fault_addr)
+}
+
+impl KernelStack {
+    /// スタックの上端 (rsp の初期値)
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// 使えるメモリの下端。この直下がガードページになる
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + PAGE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom()
+    }
+
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.guard)
+    }
+
+    /// 解放しないようにして上端を返す。IST のように使い続けるスタック向け
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top;
+        mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let (bottom, size) = (self.bottom(), self.size());
+        with_kernel_memory(|memory| unsafe { memory.unmap_range(bottom, size, true) });
+        interrupts::without_interrupts(|| KERNEL_STACKS.lock().free(self.guard));
+    }
+}
@@ -0,0 +1,252 @@
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// カーネルが動的に使う仮想アドレス範囲の先頭
+pub const KERNEL_VMA_START: u64 = 0x_7000_0000_0000;
+/// カーネルが動的に使う仮想アドレス範囲のサイズ
+pub const KERNEL_VMA_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// 使用中の仮想アドレス範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    /// 範囲を確保したもの ("vmalloc" など)
+    pub owner: &'static str,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+}
+
+/// 仮想アドレス範囲の使用状況を管理する
+///
+/// 範囲同士の間には 1 ページ以上の隙間を空け、溢れたアクセスがページフォルトになるようにする。
+pub struct VmaManager {
+    start: u64,
+    end: u64,
+    /// 先頭アドレスをキーにした使用中の範囲
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaManager {
+    pub const fn new(start: u64, size: u64) -> Self {
+        VmaManager {
+            start,
+            end: start + size,
+            areas: BTreeMap::new(),
+        }
+    }
+
+    /// size バイト (ページ単位に切り上げる) の空いている範囲を探して確保する
+    pub fn allocate(
+        &mut self,
+        size: u64,
+        flags: PageTableFlags,
+        owner: &'static str,
+    ) -> Option<Vma> {
+        self.allocate_aligned(size, PAGE_SIZE, flags, owner)
+    }
+
+    /// allocate と同様だが、先頭を align (2 のべき乗) の境界に揃える
+    pub fn allocate_aligned(
+        &mut self,
+        size: u64,
+        align: u64,
+        flags: PageTableFlags,
+        owner: &'static str,
+    ) -> Option<Vma> {
+        if size == 0 {
+            return None;
+        }
+        let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+        let align = align.max(PAGE_SIZE);
+        let align_up = |addr: u64| (addr + align - 1) & !(align - 1);
+
+        // 先頭から順に隙間を探す (first fit)。insert_fixed で記録した範囲の外の領域は見ない
+        let mut candidate = align_up(self.start);
+        for area in self.areas.range(self.start..self.end).map(|(_, area)| area) {
+            if candidate.checked_add(size)? + PAGE_SIZE <= area.start.as_u64() {
+                break;
+            }
+            candidate = candidate.max(align_up(area.end().as_u64() + PAGE_SIZE));
+        }
+        if candidate.checked_add(size)? > self.end {
+            return None;
+        }
+
+        let vma = Vma {
+            start: VirtAddr::new(candidate),
+            size,
+            flags,
+            owner,
+        };
+        self.areas.insert(candidate, vma);
+        Some(vma)
+    }
+
+    /// 位置の決まった範囲を使用中として記録する。既存の範囲と重なれば None を返す
+    ///
+    /// 管理する範囲の外でもよく、ヒープのように固定の位置にある領域も find や iter で見えるようになる。
+    pub fn insert_fixed(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        owner: &'static str,
+    ) -> Option<Vma> {
+        let vma = Vma {
+            start,
+            size,
+            flags,
+            owner,
+        };
+        let overlaps_prev = self
+            .areas
+            .range(..=start.as_u64())
+            .next_back()
+            .map_or(false, |(_, prev)| prev.end() > start);
+        let overlaps_next = self
+            .areas
+            .range(start.as_u64()..)
+            .next()
+            .map_or(false, |(_, next)| next.start < vma.end());
+        if size == 0 || overlaps_prev || overlaps_next {
+            return None;
+        }
+
+        self.areas.insert(start.as_u64(), vma);
+        Some(vma)
+    }
+
+    /// start から始まる範囲を解放する
+    pub fn free(&mut self, start: VirtAddr) -> Option<Vma> {
+        self.areas.remove(&start.as_u64())
+    }
+
+    /// addr を含む範囲を返す
+    pub fn find(&self, addr: VirtAddr) -> Option<Vma> {
+        let (_, vma) = self.areas.range(..=addr.as_u64()).next_back()?;
+        if vma.contains(addr) {
+            Some(*vma)
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+}
+
+static KERNEL_VMAS: Mutex<VmaManager> =
+    Mutex::new(VmaManager::new(KERNEL_VMA_START, KERNEL_VMA_SIZE));
+
+/// カーネルの仮想アドレス範囲を確保する。ページのマップは呼び出し元が行う
+pub fn reserve(size: u64, flags: PageTableFlags, owner: &'static str) -> Option<Vma> {
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().allocate(size, flags, owner))
+}
+
+/// reserve と同様だが、先頭を align の境界に揃える
+pub fn reserve_aligned(
+    size: u64,
+    align: u64,
+    flags: PageTableFlags,
+    owner: &'static str,
+) -> Option<Vma> {
+    interrupts::without_interrupts(|| {
+        KERNEL_VMAS
+            .lock()
+            .allocate_aligned(size, align, flags, owner)
+    })
+}
+
+/// 位置の決まった範囲を使用中として記録する。既存の範囲と重なれば None を返す
+pub fn reserve_fixed(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    owner: &'static str,
+) -> Option<Vma> {
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().insert_fixed(start, size, flags, owner))
+}
+
+/// reserve で確保した範囲を解放する。ページのマップ解除は呼び出し元が行う
+pub fn release(start: VirtAddr) -> Option<Vma> {
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().free(start))
+}
+
+/// addr を含むカーネルの仮想アドレス範囲を返す
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().find(addr))
+}
+
+/// 使用中の範囲それぞれについて f を呼ぶ
+pub fn for_each(mut f: impl FnMut(&Vma)) {
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().iter().for_each(|vma| f(vma)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn areas_do_not_overlap() {
+        let mut vmas = VmaManager::new(0x_1000_0000, 64 * PAGE_SIZE);
+        let flags = PageTableFlags::WRITABLE;
+
+        let a = vmas.allocate(1, flags, "a").unwrap();
+        let b = vmas.allocate(3 * PAGE_SIZE, flags, "b").unwrap();
+        assert_eq!(a.size, PAGE_SIZE);
+        // ガードページの分だけ離れる
+        assert_eq!(b.start, a.end() + PAGE_SIZE);
+        assert_eq!(vmas.find(b.start + 5u64).map(|vma| vma.owner), Some("b"));
+        assert_eq!(vmas.find(a.end()), None);
+
+        // 解放した隙間は再利用される
+        vmas.free(a.start).unwrap();
+        let c = vmas.allocate(PAGE_SIZE, flags, "c").unwrap();
+        assert_eq!(c.start, a.start);
+
+        assert!(vmas.allocate(64 * PAGE_SIZE, flags, "too large").is_none());
+    }
+
+    #[test_case]
+    fn fixed_areas_are_recorded() {
+        let mut vmas = VmaManager::new(0x_1000_0000, 64 * PAGE_SIZE);
+        let flags = PageTableFlags::WRITABLE;
+
+        // 管理する範囲の外にある固定の範囲は、割り当てに影響しない
+        let fixed = VirtAddr::new(0x_0800_0000);
+        vmas.insert_fixed(fixed, 16 * PAGE_SIZE, flags, "fixed")
+            .unwrap();
+        assert_eq!(
+            vmas.find(fixed + 5 * PAGE_SIZE).map(|vma| vma.owner),
+            Some("fixed")
+        );
+        assert!(vmas
+            .insert_fixed(fixed + 15 * PAGE_SIZE, PAGE_SIZE, flags, "overlap")
+            .is_none());
+        assert_eq!(
+            vmas.allocate(64 * PAGE_SIZE, flags, "all")
+                .unwrap()
+                .start
+                .as_u64(),
+            0x_1000_0000
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+use super::{vma, with_kernel_memory};
+
+/// vmalloc で確保した範囲の owner
+pub const VMALLOC_OWNER: &str = "vmalloc";
+
+#[derive(Debug)]
+pub enum VmallocError {
+    /// 空いている仮想アドレス範囲がない
+    OutOfVirtualSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+/// size バイトの仮想アドレス範囲を確保し、新しいフレームを flags でマップする
+///
+/// 物理的に連続していない大きなバッファ向けで、ヒープとは独立している。
+/// 確保したメモリはゼロで埋められる。
+pub fn vmalloc(size: usize, flags: PageTableFlags) -> Result<VirtAddr, VmallocError> {
+    // 2MiB 以上なら大きなページを使えるよう境界を揃える
+    let align = if size as u64 >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        Size4KiB::SIZE
+    };
+    let area = vma::reserve_aligned(size as u64, align, flags, VMALLOC_OWNER)
+        .ok_or(VmallocError::OutOfVirtualSpace)?;
+    let flags = flags | PageTableFlags::PRESENT;
+
+    let result = with_kernel_memory(|memory| {
+        let mut mapped = 0;
+        while mapped < area.size {
+            match memory.map_new_chunk(area.start + mapped, area.size - mapped, flags) {
+                Ok(size) => mapped += size,
+                Err(err) => {
+                    // マップ済みのページを戻す
+                    unsafe { memory.unmap_range(area.start, mapped, true) };
+                    return Err(VmallocError::Map(err));
+                }
+            }
+        }
+        Ok(())
+    })
+    .expect("kernel memory not initialized");
+
+    match result {
+        Ok(()) => Ok(area.start),
+        Err(err) => {
+            vma::release(area.start);
+            Err(err)
+        }
+    }
+}
+
+/// vmalloc で確保した範囲を解放する
+///
+/// addr が vmalloc の返したアドレスでなければパニックする。
+/// 呼び出し元は、範囲内のメモリへの参照が残っていないことを保証しなければならない。
+pub unsafe fn vfree(addr: VirtAddr) {
+    match vma::find(addr) {
+        Some(area) if area.start == addr && area.owner == VMALLOC_OWNER => {}
+        _ => panic!("vfree of an address not returned by vmalloc: {:?}", addr),
+    }
+
+    let area = vma::release(addr).unwrap();
+    with_kernel_memory(|memory| memory.unmap_range(area.start, area.size, true));
+}
@@ -0,0 +1,330 @@
+use core::fmt;
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use crate::serial_println;
+
+const ENTRY_COUNT: usize = 512;
+/// ページテーブルの段数。深さ 0 が L4、深さ 3 が L1
+const LEVELS: usize = 4;
+
+/// 連続してマップされた範囲
+///
+/// 仮想アドレスと物理アドレスがともに連続し、フラグとページの大きさが同じページをまとめたもの。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub phys: PhysAddr,
+    /// ACCESSED と DIRTY を除いたフラグ。ヒュージページでは HUGE_PAGE も除く
+    pub flags: PageTableFlags,
+    pub page_size: u64,
+}
+
+impl MappedRange {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// next が直後に続き、一つの範囲にまとめられるか
+    fn can_merge(&self, next: &MappedRange) -> bool {
+        self.end() == next.start
+            && self.phys + self.size == next.phys
+            && self.flags == next.flags
+            && self.page_size == next.page_size
+    }
+}
+
+/// 変換の結果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    pub flags: PageTableFlags,
+    pub page_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslateError {
+    /// level (4 から 1) のテーブルのエントリが存在しない
+    NotPresent { level: u8 },
+    /// level のテーブルのエントリに、そのレベルでは使えない HUGE_PAGE が立っている
+    InvalidHugePage { level: u8 },
+}
+
+/// 4 レベルのページテーブルを辿る
+pub struct PageTableWalker<'a> {
+    level_4_table: &'a PageTable,
+    physical_memory_offset: VirtAddr,
+}
+
+impl<'a> PageTableWalker<'a> {
+    /// level_4_frame をレベル4テーブルとして辿る Walker を作る
+    ///
+    /// 呼び出し元は、level_4_frame が有効なレベル4テーブルで、全物理メモリが
+    /// physical_memory_offset からマップされていて、'a の間テーブルが解放されないことを保証しなければならない。
+    pub unsafe fn new(level_4_frame: PhysFrame, physical_memory_offset: VirtAddr) -> Self {
+        PageTableWalker {
+            level_4_table: table_at(physical_memory_offset, level_4_frame.start_address()),
+            physical_memory_offset,
+        }
+    }
+
+    /// CR3 に設定されているページテーブルを辿る Walker を作る
+    ///
+    /// new と同じ条件を満たさなければならない。
+    pub unsafe fn active(physical_memory_offset: VirtAddr) -> Self {
+        Self::new(Cr3::read().0, physical_memory_offset)
+    }
+
+    /// マップされている範囲を、連続したものをまとめて仮想アドレス順に返す
+    pub fn ranges(&self) -> MappedRanges<'a> {
+        MappedRanges {
+            physical_memory_offset: self.physical_memory_offset,
+            tables: [self.level_4_table; LEVELS],
+            indices: [0; LEVELS],
+            depth: 0,
+            pending: None,
+        }
+    }
+
+    /// addr を物理アドレスに変換する。失敗したときは失敗したレベルを返す
+    pub fn translate_addr(&self, addr: VirtAddr) -> Result<Translation, TranslateError> {
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+
+        let mut table = self.level_4_table;
+        for (depth, &index) in indices.iter().enumerate() {
+            let level = (LEVELS - depth) as u8;
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return Err(TranslateError::NotPresent { level });
+            }
+
+            let page_size = page_size(depth);
+            if depth == LEVELS - 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+                if depth == 0 {
+                    return Err(TranslateError::InvalidHugePage { level });
+                }
+                return Ok(Translation {
+                    phys: entry.addr() + (addr.as_u64() & (page_size - 1)),
+                    flags: leaf_flags(depth, flags),
+                    page_size,
+                });
+            }
+
+            table = unsafe { table_at(self.physical_memory_offset, entry.addr()) };
+        }
+        unreachable!()
+    }
+
+    /// マップされている範囲をシリアルに書き出す
+    pub fn dump(&self) {
+        serial_println!(
+            "{:<18} {:<18} {:<18} {:>9} {:>4}  flags",
+            "start",
+            "end",
+            "phys",
+            "size",
+            "page"
+        );
+        let mut count = 0;
+        for range in self.ranges() {
+            serial_println!(
+                "{:#018x} {:#018x} {:#018x} {:>9} {:>4}  {}",
+                range.start.as_u64(),
+                range.end().as_u64(),
+                range.phys.as_u64(),
+                Size(range.size),
+                Size(range.page_size),
+                Flags(range.flags)
+            );
+            count += 1;
+        }
+        serial_println!("# {} ranges", count);
+    }
+}
+
+/// 有効なページテーブルの内容をシリアルに書き出す
+///
+/// キーボードのタスクから F12 で呼ばれる。カーネルのページテーブルが登録されていなければ何もしない。
+pub fn dump_active() {
+    let physical_memory_offset =
+        match super::with_kernel_memory(|memory| memory.mapper.phys_offset()) {
+            Some(offset) => offset,
+            None => return,
+        };
+
+    // 読むだけなので、辿っている間はロックを保持しない
+    let walker = unsafe { PageTableWalker::active(physical_memory_offset) };
+    walker.dump();
+}
+
+/// `PageTableWalker::ranges` が返すイテレータ
+pub struct MappedRanges<'a> {
+    physical_memory_offset: VirtAddr,
+    /// 深さごとに辿っているテーブル
+    tables: [&'a PageTable; LEVELS],
+    /// 深さごとに次に見るエントリの番号
+    indices: [usize; LEVELS],
+    depth: usize,
+    /// まだ続くページがあるかもしれない範囲
+    pending: Option<MappedRange>,
+}
+
+impl<'a> MappedRanges<'a> {
+    /// 次にマップされているページを返す
+    fn next_page(&mut self) -> Option<MappedRange> {
+        loop {
+            let depth = self.depth;
+            let index = self.indices[depth];
+            if index == ENTRY_COUNT {
+                if depth == 0 {
+                    return None;
+                }
+                self.depth -= 1;
+                self.indices[self.depth] += 1;
+                continue;
+            }
+
+            let entry = &self.tables[depth][index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                self.indices[depth] += 1;
+                continue;
+            }
+
+            let is_huge = flags.contains(PageTableFlags::HUGE_PAGE);
+            // L4 の HUGE_PAGE は無効なので、translate_addr と同じく何もマップしていないものとして扱う
+            if depth == 0 && is_huge {
+                self.indices[depth] += 1;
+                continue;
+            }
+            if depth == LEVELS - 1 || is_huge {
+                self.indices[depth] += 1;
+                return Some(MappedRange {
+                    start: self.current_addr(depth, index),
+                    size: page_size(depth),
+                    phys: entry.addr(),
+                    flags: leaf_flags(depth, flags),
+                    page_size: page_size(depth),
+                });
+            }
+
+            self.tables[depth + 1] = unsafe { table_at(self.physical_memory_offset, entry.addr()) };
+            self.indices[depth + 1] = 0;
+            self.depth += 1;
+        }
+    }
+
+    /// depth のテーブルの index 番目のエントリが指す仮想アドレス
+    fn current_addr(&self, depth: usize, index: usize) -> VirtAddr {
+        let addr = self.indices[..depth]
+            .iter()
+            .chain(core::iter::once(&index))
+            .enumerate()
+            .fold(0, |addr, (d, &i)| {
+                addr | (i as u64) << (12 + 9 * (LEVELS - 1 - d))
+            });
+        // bit 47 を符号拡張する
+        VirtAddr::new_truncate(addr)
+    }
+}
+
+impl<'a> Iterator for MappedRanges<'a> {
+    type Item = MappedRange;
+
+    fn next(&mut self) -> Option<MappedRange> {
+        while let Some(page) = self.next_page() {
+            match &mut self.pending {
+                Some(range) if range.can_merge(&page) => range.size += page.size,
+                pending => {
+                    if let Some(range) = pending.replace(page) {
+                        return Some(range);
+                    }
+                }
+            }
+        }
+        self.pending.take()
+    }
+}
+
+/// depth のテーブルのエントリ 1 つが指す大きさ
+fn page_size(depth: usize) -> u64 {
+    4096 << (9 * (LEVELS - 1 - depth))
+}
+
+/// まとめるときに邪魔になるフラグを除く
+///
+/// L1 のエントリでは HUGE_PAGE と同じ位置のビットは PAT なので残す。
+fn leaf_flags(depth: usize, flags: PageTableFlags) -> PageTableFlags {
+    let mut flags = flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+    if depth < LEVELS - 1 {
+        flags -= PageTableFlags::HUGE_PAGE;
+    }
+    flags
+}
+
+unsafe fn table_at<'a>(physical_memory_offset: VirtAddr, addr: PhysAddr) -> &'a PageTable {
+    let virt = physical_memory_offset + addr.as_u64();
+    &*virt.as_ptr()
+}
+
+/// バイト数を KiB, MiB, GiB のうち割り切れる最大の単位で表示する
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [(u64, &str); 4] = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K"), (1, "")];
+        let &(unit, suffix) = UNITS
+            .iter()
+            .find(|&&(unit, _)| self.0 >= unit && self.0 % unit == 0)
+            .unwrap_or(&(1, ""));
+        // ヒープを使わずに幅を揃えるため、数値と単位を分けて書く
+        let width = f.width().unwrap_or(0).saturating_sub(suffix.len());
+        write!(f, "{:>width$}{}", self.0 / unit, suffix, width = width)
+    }
+}
+
+/// フラグを短い名前で表示する
+struct Flags(PageTableFlags);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+        let writable = if flags.contains(PageTableFlags::WRITABLE) {
+            "rw"
+        } else {
+            "r-"
+        };
+        let executable = if flags.contains(PageTableFlags::NO_EXECUTE) {
+            "-"
+        } else {
+            "x"
+        };
+        write!(f, "{}{}", writable, executable)?;
+
+        let names = [
+            (PageTableFlags::USER_ACCESSIBLE, "user"),
+            (PageTableFlags::GLOBAL, "global"),
+            (PageTableFlags::WRITE_THROUGH, "pwt"),
+            (PageTableFlags::NO_CACHE, "pcd"),
+            // ヒュージページの HUGE_PAGE は除いてあるので、残っていれば L1 の PAT ビット
+            (PageTableFlags::HUGE_PAGE, "pat"),
+        ];
+        for &(flag, name) in names.iter() {
+            if flags.contains(flag) {
+                write!(f, " {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,62 +1,183 @@
-use core::task::{Context, Poll, Waker};
+use core::{
+    alloc::AllocError,
+    fmt,
+    task::{Context, Poll, Waker},
+};
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use alloc::{sync::Arc, task::Wake, vec::Vec};
 use crossbeam_queue::ArrayQueue;
 
 use super::{Task, TaskId};
+use crate::{
+    allocator::{self, oom, trace},
+    println,
+};
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    // BTreeMap には失敗を返す挿入がないので、try_reserve できる Vec にタスクを置く。
+    // キューにはタスクの位置も入れるので、起こされたタスクを探さずに済む
+    tasks: Vec<Option<TaskEntry>>,
+    /// tasks のうち空いている位置。タスクの終了時に割り当てずに済むよう、tasks と同じ数だけ確保しておく
+    free_slots: Vec<usize>,
+    task_queue: Arc<ArrayQueue<Wakeup>>,
+}
+
+/// 実行中のタスクと、その位置をキューに入れる Waker
+struct TaskEntry {
+    task: Task,
+    waker: Waker,
+}
+
+/// キューに入れる、起こすタスクの ID と tasks 上の位置
+///
+/// 終了したタスクの位置は再利用されるので、ID が一致するときだけ実行する。
+#[derive(Debug, Clone, Copy)]
+struct Wakeup {
+    task_id: TaskId,
+    slot: usize,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Executor {
-            tasks: BTreeMap::new(),
+            tasks: Vec::new(),
+            free_slots: Vec::new(),
             task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
+        let slot = self.reserve_slot().expect("failed to allocate task slot");
+        let waker = TaskWaker::new(task.id, slot, self.task_queue.clone());
+        self.insert(slot, task, waker);
+    }
+
+    /// メモリ不足で新しい仕事を断っている間、タスクや Waker を格納する領域を確保できないとき、
+    /// キューが一杯のときは、タスクをそのまま Err で返す
+    pub fn try_spawn(&mut self, task: Task) -> Result<(), SpawnError> {
+        if oom::refuses_new_work() {
+            return Err(SpawnError::OutOfMemory(task));
+        }
+        if self.task_queue.is_full() {
+            return Err(SpawnError::QueueFull(task));
+        }
+
+        let slot = match self.reserve_slot() {
+            Ok(slot) => slot,
+            Err(_) => return Err(SpawnError::OutOfMemory(task)),
+        };
+        let waker = match TaskWaker::try_new(task.id, slot, self.task_queue.clone()) {
+            Ok(waker) => waker,
+            Err(_) => return Err(SpawnError::OutOfMemory(task)),
+        };
+
+        self.insert(slot, task, waker);
+        Ok(())
+    }
+
+    /// 空いている位置を返す。なければ tasks と free_slots の領域を広げて末尾の位置を返す
+    ///
+    /// 返した位置は insert で埋めるまで空いたまま残る。
+    fn reserve_slot(&mut self) -> Result<usize, AllocError> {
+        if let Some(&slot) = self.free_slots.last() {
+            return Ok(slot);
+        }
+
+        // free_slots は空なので、tasks.len() + 1 個まで入るよう確保する
+        self.tasks.try_reserve(1).map_err(|_| AllocError)?;
+        self.free_slots
+            .try_reserve(self.tasks.len() + 1)
+            .map_err(|_| AllocError)?;
+        Ok(self.tasks.len())
+    }
+
+    /// 領域は呼び出し元が reserve_slot で確保しておく
+    fn insert(&mut self, slot: usize, task: Task, waker: Waker) {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("task with same ID already in tasks");
+        let entry = Some(TaskEntry { task, waker });
+
+        if slot < self.tasks.len() {
+            let popped = self.free_slots.pop();
+            debug_assert_eq!(popped, Some(slot));
+            self.tasks[slot] = entry;
+        } else {
+            self.tasks.push(entry);
         }
+        self.task_queue
+            .push(Wakeup { task_id, slot })
+            .expect("queue full");
+    }
 
-        self.task_queue.push(task_id).expect("queue full");
+    /// 終了したタスクの位置を空ける。free_slots の領域は spawn 時に確保済み
+    fn remove(
+        tasks: &mut [Option<TaskEntry>],
+        free_slots: &mut Vec<usize>,
+        slot: usize,
+    ) -> Option<TaskEntry> {
+        let entry = tasks[slot].take();
+        if entry.is_some() {
+            debug_assert!(free_slots.len() < free_slots.capacity());
+            free_slots.push(slot);
+        }
+        entry
     }
 
-    fn run_ready_tasks(&mut self) {
+    /// キューにあるタスクを、キューが空になるまで実行する
+    pub fn run_ready_tasks(&mut self) {
         let Self {
             tasks,
+            free_slots,
             task_queue,
-            waker_cache,
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // タスクが存在しない
+        while let Ok(Wakeup { task_id, slot }) = task_queue.pop() {
+            let entry = match tasks.get_mut(slot) {
+                Some(Some(entry)) if entry.task.id == task_id => entry,
+                _ => continue, // タスクはすでに終了している
             };
+            let task = &mut entry.task;
+
+            let mut context = Context::from_waker(&entry.waker);
+            let live_before = allocator::live_bytes();
+            // タスク内の割り当てをタスク ID で追跡できるようにする
+            let poll = trace::with_tag(task_id.0, || task.poll(&mut context));
+            // poll の間に増えた分をこのタスクのメモリとして数える
+            let live_after = allocator::live_bytes();
+            if live_after >= live_before {
+                task.memory = task.memory.saturating_add(live_after - live_before);
+            } else {
+                task.memory = task.memory.saturating_sub(live_before - live_after);
+            }
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+            if let Poll::Ready(()) = poll {
+                Self::remove(tasks, free_slots, slot);
+            }
+
+            if oom::take_kill_request() {
+                Self::kill_largest_task(tasks, free_slots);
             }
         }
     }
 
+    /// 最も多くのメモリを使っているタスクを終了させる
+    fn kill_largest_task(tasks: &mut [Option<TaskEntry>], free_slots: &mut Vec<usize>) {
+        let slot = match tasks
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, entry)| Some((slot, entry.as_ref()?.task.memory)))
+            .max_by_key(|&(_, memory)| memory)
+        {
+            Some((slot, _)) => slot,
+            None => return,
+        };
+
+        let task = Self::remove(tasks, free_slots, slot).unwrap().task;
+        println!(
+            "WARNING: out of memory; killing task {} ({} bytes)",
+            task.id.0, task.memory
+        );
+    }
+
     pub fn run(&mut self) -> ! {
         loop {
             self.run_ready_tasks();
@@ -76,21 +197,57 @@ impl Executor {
     }
 }
 
+/// try_spawn が受け付けなかった理由。タスクは呼び出し元に返す
+pub enum SpawnError {
+    /// メモリ不足で新しいタスクを断っている
+    OutOfMemory(Task),
+    /// タスクキューが一杯
+    QueueFull(Task),
+}
+
+impl SpawnError {
+    pub fn into_task(self) -> Task {
+        match self {
+            SpawnError::OutOfMemory(task) | SpawnError::QueueFull(task) => task,
+        }
+    }
+}
+
+// Task は Debug を実装していないので、理由だけを表示する
+impl fmt::Debug for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnError::OutOfMemory(_) => f.write_str("OutOfMemory"),
+            SpawnError::QueueFull(_) => f.write_str("QueueFull"),
+        }
+    }
+}
+
 struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    wakeup: Wakeup,
+    task_queue: Arc<ArrayQueue<Wakeup>>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
+    fn new(task_id: TaskId, slot: usize, task_queue: Arc<ArrayQueue<Wakeup>>) -> Waker {
+        Self::try_new(task_id, slot, task_queue).expect("failed to allocate waker")
+    }
+
+    /// Waker をヒープに置けなければ Err を返す
+    fn try_new(
+        task_id: TaskId,
+        slot: usize,
+        task_queue: Arc<ArrayQueue<Wakeup>>,
+    ) -> Result<Waker, AllocError> {
+        let waker = Arc::try_new(TaskWaker {
+            wakeup: Wakeup { task_id, slot },
             task_queue,
-        }))
+        })?;
+        Ok(Waker::from(waker))
     }
 
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.task_queue.push(self.wakeup).expect("task_queue full");
     }
 }
 
@@ -103,3 +260,51 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::{
+        future,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+    use spin::Mutex;
+
+    #[test_case]
+    fn finished_task_slots_are_reused() {
+        let mut executor = Executor::new();
+        for _ in 0..3 {
+            executor.spawn(Task::new(async {}));
+            executor.run_ready_tasks();
+        }
+        assert_eq!(executor.tasks.len(), 1);
+        assert_eq!(executor.free_slots, [0]);
+    }
+
+    static STALE_WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[test_case]
+    fn stale_wakeup_does_not_poll_new_task() {
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(future::poll_fn(|context| {
+            *STALE_WAKER.lock() = Some(context.waker().clone());
+            Poll::Ready(())
+        })));
+        executor.run_ready_tasks();
+
+        // 終了したタスクの位置を再利用する
+        executor.spawn(Task::new(future::poll_fn(|_| {
+            POLLS.fetch_add(1, Ordering::SeqCst);
+            Poll::<()>::Pending
+        })));
+        executor.run_ready_tasks();
+        assert_eq!(POLLS.load(Ordering::SeqCst), 1);
+
+        // 終了したタスクの Waker で起こしても、同じ位置の別のタスクは実行されない
+        let waker = STALE_WAKER.lock().take().unwrap();
+        waker.wake();
+        executor.run_ready_tasks();
+        assert_eq!(POLLS.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,16 +1,69 @@
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 
-use crate::{print, println};
+use crate::{allocator::oom, memory, print, println};
 
+use alloc::{boxed::Box, vec::Vec};
 use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
 use futures_util::{task::AtomicWaker, Stream, StreamExt};
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static SCANCODE_QUEUE: OnceCell<ScancodeQueue> = OnceCell::uninit();
+
+const SCANCODE_QUEUE_CAPACITY: usize = 100;
+
+/// 割り込みハンドラ (書き手) とキーボード処理タスク (読み手) の間のリングバッファ
+///
+/// ArrayQueue::new は割り当てに失敗するとパニックするので、領域を try_reserve で確保できるものを使う。
+/// 書き手と読み手がそれぞれ 1 つだけなので、ロックを使わずに済む。
+struct ScancodeQueue {
+    /// 満杯と空を区別するため、容量より 1 つ多く持つ
+    slots: Box<[AtomicU8]>,
+    /// 次に読む位置
+    head: AtomicUsize,
+    /// 次に書く位置
+    tail: AtomicUsize,
+}
+
+impl ScancodeQueue {
+    fn try_new(capacity: usize) -> Option<Self> {
+        let mut slots = Vec::new();
+        slots.try_reserve_exact(capacity + 1).ok()?;
+        slots.extend((0..=capacity).map(|_| AtomicU8::new(0)));
+        Some(ScancodeQueue {
+            slots: slots.into_boxed_slice(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        })
+    }
+
+    /// 割り込みハンドラからだけ呼ぶ。満杯なら Err を返す
+    fn push(&self, scancode: u8) -> Result<(), ()> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % self.slots.len();
+        if next == self.head.load(Ordering::Acquire) {
+            return Err(());
+        }
+        self.slots[tail].store(scancode, Ordering::Relaxed);
+        self.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    /// キーボード処理タスクからだけ呼ぶ
+    fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = self.slots[head].load(Ordering::Relaxed);
+        self.head
+            .store((head + 1) % self.slots.len(), Ordering::Release);
+        Some(scancode)
+    }
+}
 static WAKER: AtomicWaker = AtomicWaker::new();
 
 /// キーボード割り込みハンドラから呼ばれる
@@ -33,12 +86,34 @@ pub struct ScancodeStream {
     _private: (),
 }
 
+/// スキャンコードのキューを初期化できなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeQueueError {
+    /// すでに初期化されている
+    AlreadyInitialized,
+    /// メモリ不足でキューを確保できなかった
+    OutOfMemory,
+}
+
 impl ScancodeStream {
     pub fn new() -> Self {
+        ScancodeStream::try_new().expect("ScancodeStream::new should only be called once")
+    }
+
+    pub fn try_new() -> Result<Self, ScancodeQueueError> {
+        if SCANCODE_QUEUE.is_initialized() {
+            return Err(ScancodeQueueError::AlreadyInitialized);
+        }
+        if oom::refuses_new_work() {
+            return Err(ScancodeQueueError::OutOfMemory);
+        }
+
+        let queue = ScancodeQueue::try_new(SCANCODE_QUEUE_CAPACITY)
+            .ok_or(ScancodeQueueError::OutOfMemory)?;
         SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+            .try_init_once(|| queue)
+            .map_err(|_| ScancodeQueueError::AlreadyInitialized)?;
+        Ok(ScancodeStream { _private: () })
     }
 }
 
@@ -48,7 +123,7 @@ impl Stream for ScancodeStream {
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
 
-        if let Ok(scancode) = queue.pop() {
+        if let Some(scancode) = queue.pop() {
             return Poll::Ready(Some(scancode));
         }
 
@@ -56,12 +131,12 @@ impl Stream for ScancodeStream {
         WAKER.register(&cx.waker());
 
         match queue.pop() {
-            Ok(scannode) => {
+            Some(scannode) => {
                 // 登録解除
                 WAKER.take();
                 Poll::Ready(Some(scannode))
             }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
+            None => Poll::Pending,
         }
     }
 }
@@ -75,6 +150,8 @@ pub async fn print_keypresses() {
             if let Some(key) = keyboard.process_keyevent(key_event) {
                 match key {
                     DecodedKey::Unicode(character) => print!("{}", character),
+                    // F12 でページテーブルの内容をシリアルに書き出す
+                    DecodedKey::RawKey(KeyCode::F12) => memory::walk::dump_active(),
                     DecodedKey::RawKey(key) => print!("{:?}", key),
                 }
             }
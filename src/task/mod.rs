@@ -1,5 +1,7 @@
 use core::{
+    alloc::AllocError,
     future::Future,
+    mem,
     pin::Pin,
     sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
@@ -24,14 +26,24 @@ impl TaskId {
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
+    /// このタスクが確保しているとみなすバイト数。OOM 時に終了させるタスクを選ぶのに使う
+    memory: usize,
 }
 
 impl Task {
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task {
+        Task::try_new(future).expect("failed to allocate task")
+    }
+
+    /// Future をヒープに置けなければ Err を返す
+    pub fn try_new(future: impl Future<Output = ()> + 'static) -> Result<Task, AllocError> {
+        let memory = mem::size_of_val(&future);
+        let future: Pin<Box<dyn Future<Output = ()>>> = Box::into_pin(Box::try_new(future)?);
+        Ok(Task {
             id: TaskId::new(),
-            future: Box::pin(future),
-        }
+            future,
+            memory,
+        })
     }
 
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::{
+    allocator,
+    memory::{
+        self,
+        address_space::{AddressSpace, AddressSpaceError},
+        stack, vma, vmalloc,
+    },
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// カーネルが使っていない L4 エントリの範囲にあるアドレス
+const PRIVATE_ADDR: u64 = 0x_6000_0000_0000;
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+fn kernel_translate(addr: VirtAddr) -> Option<x86_64::PhysAddr> {
+    memory::with_kernel_memory(|memory| memory.mapper.translate_addr(addr)).unwrap()
+}
+
+#[test_case]
+fn mapping_is_private_to_address_space() {
+    let used_before = used_frames();
+    let frame = memory::with_kernel_memory(|memory| memory.frame_allocator.allocate_frame())
+        .unwrap()
+        .expect("out of frames");
+
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    let addr = VirtAddr::new(PRIVATE_ADDR);
+    let page = Page::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    space.map(page, frame, flags).expect("map failed");
+
+    assert_eq!(space.translate(addr), Some(frame.start_address()));
+    assert_eq!(kernel_translate(addr), None);
+
+    // カーネルの領域はどちらのアドレス空間でも同じ物理アドレスになる
+    let heap_value = Box::new(42u64);
+    let heap_addr = VirtAddr::from_ptr(&*heap_value);
+    assert_eq!(space.translate(heap_addr), kernel_translate(heap_addr));
+
+    let previous = unsafe { space.switch_to() };
+    assert!(space.is_active());
+    unsafe {
+        let ptr: *mut u64 = addr.as_mut_ptr();
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert_eq!(*heap_value, 42);
+    unsafe { Cr3::write(previous.0, previous.1) };
+    assert!(!space.is_active());
+
+    assert_eq!(space.unmap(page).expect("unmap failed"), frame);
+    assert_eq!(space.translate(addr), None);
+
+    drop(space);
+    memory::with_kernel_memory(|memory| unsafe { memory.frame_allocator.deallocate_frame(frame) });
+    drop(heap_value);
+    assert_eq!(used_frames(), used_before);
+}
+
+#[test_case]
+fn kernel_entries_cannot_be_changed() {
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    let page = Page::containing_address(VirtAddr::new(allocator::HEAP_START as u64));
+
+    assert!(matches!(
+        space.unmap(page),
+        Err(AddressSpaceError::SharedWithKernel)
+    ));
+}
+
+#[test_case]
+fn later_kernel_mappings_are_visible() {
+    // vma の範囲にまだ何もマップしていない状態で作る
+    let space = AddressSpace::new().expect("failed to create address space");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let addr = vmalloc::vmalloc(4096, flags).expect("vmalloc failed");
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(7) };
+
+    assert_eq!(space.translate(addr), kernel_translate(addr));
+    let previous = unsafe { space.switch_to() };
+    let value = unsafe { addr.as_ptr::<u64>().read_volatile() };
+    unsafe { Cr3::write(previous.0, previous.1) };
+    assert_eq!(value, 7);
+
+    unsafe { vmalloc::vfree(addr) };
+}
+
+#[test_case]
+fn kernel_slots_are_reserved() {
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    let frame = memory::with_kernel_memory(|memory| memory.frame_allocator.allocate_frame())
+        .unwrap()
+        .expect("out of frames");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // カーネルが後から使う範囲には、作成時に何もマップされていなくてもマップできない
+    for &start in [vma::KERNEL_VMA_START, stack::KERNEL_STACK_START].iter() {
+        let page = Page::containing_address(VirtAddr::new(start));
+        assert!(matches!(
+            space.map(page, frame, flags),
+            Err(AddressSpaceError::SharedWithKernel)
+        ));
+    }
+
+    memory::with_kernel_memory(|memory| unsafe { memory.frame_allocator.deallocate_frame(frame) });
+}
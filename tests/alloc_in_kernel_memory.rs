@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::panic::PanicInfo;
+
+use blog_os::{
+    exit_qemu,
+    memory::{self},
+    serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{entry_point, BootInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("alloc_in_kernel_memory::allocation_is_rejected...\t");
+    // 検査は debug_assert で行うので、release ビルドでは確かめられない
+    if !cfg!(debug_assertions) {
+        serial_println!("[ignored]");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    // ロックを保持したまま割り当てると、ヒープを拡張できずに黙って失敗するおそれがある
+    memory::with_kernel_memory(|_| Box::new(0u64));
+
+    serial_println!("[failed]\nAllocation while holding KERNEL_MEMORY was not detected");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "while holding KERNEL_MEMORY")
+}
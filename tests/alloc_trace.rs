@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::allocator::{
+    self,
+    trace::{self, EventKind},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// 前のテストで残ったイベントを捨てる
+fn discard_events() {
+    while trace::pop_event().is_some() {}
+}
+
+#[test_case]
+fn records_tagged_alloc_and_dealloc() {
+    trace::enable();
+    discard_events();
+
+    let addr = trace::with_tag(42, || {
+        let value = Box::new([1u8; 100]);
+        &*value as *const [u8; 100] as usize
+    });
+    trace::disable();
+
+    let mut allocated = false;
+    let mut freed = false;
+    while let Some(event) = trace::pop_event() {
+        if event.addr != addr || event.size != 100 {
+            continue;
+        }
+        assert_eq!(event.tag, 42);
+        match event.kind {
+            EventKind::Alloc => allocated = true,
+            EventKind::Dealloc => freed = allocated,
+        }
+    }
+    assert!(allocated && freed);
+}
+
+/// Box を 10 個割り当てて解放し、その間に増えた割り当ての回数を返す
+fn count_allocations_for_ten_boxes() -> usize {
+    let before = allocator::stats().allocations;
+    for i in 0..10 {
+        let value = Box::new(i);
+        assert_eq!(*value, i);
+    }
+    allocator::stats().allocations - before
+}
+
+#[test_case]
+fn tracing_does_not_allocate() {
+    assert_eq!(count_allocations_for_ten_boxes(), 10);
+
+    // 記録の処理自体が割り当てを行っていれば、回数が Box の数より多くなる
+    trace::enable();
+    discard_events();
+    let traced = count_allocations_for_ten_boxes();
+    trace::disable();
+    assert_eq!(traced, 10);
+
+    let mut alloc_events = 0;
+    while let Some(event) = trace::pop_event() {
+        if event.kind == EventKind::Alloc {
+            alloc_events += 1;
+        }
+    }
+    assert_eq!(alloc_events, 10);
+}
+
+#[test_case]
+fn full_buffer_drops_events() {
+    trace::enable();
+    discard_events();
+
+    let dropped_before = trace::dropped_events();
+    for i in 0..2048 {
+        drop(Box::new(i));
+    }
+    trace::disable();
+
+    assert!(trace::dropped_events() > dropped_before);
+    discard_events();
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{
+    exit_qemu, memory::buddy::BuddyFrameAllocator, serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    merged_block_double_free_is_detected(&mut frame_allocator);
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "double free")
+}
+
+fn merged_block_double_free_is_detected(allocator: &mut BuddyFrameAllocator) {
+    serial_print!("buddy_double_free::merged_block_double_free_is_detected...\t");
+
+    let a = allocator.allocate(0).expect("out of frames");
+    let b = allocator.allocate(0).expect("out of frames");
+    unsafe {
+        allocator.deallocate(a, 0);
+        allocator.deallocate(b, 0);
+        // a はバディと結合されて大きなブロックの一部になっているが、それでも二重解放とわかる
+        allocator.deallocate(a, 0);
+    }
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::buddy::BuddyFrameAllocator;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB},
+    VirtAddr,
+};
+
+static FRAME_ALLOCATOR: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn blocks_are_aligned_to_their_order() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    for order in 0..6 {
+        let frame = allocator.allocate(order).expect("out of frames");
+        assert!(frame.start_address().is_aligned(4096u64 << order));
+        unsafe { allocator.deallocate(frame, order) };
+    }
+}
+
+#[test_case]
+fn split_blocks_are_merged_on_free() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+    let blocks_before: [usize; 4] = [
+        allocator.free_blocks(0),
+        allocator.free_blocks(1),
+        allocator.free_blocks(2),
+        allocator.free_blocks(3),
+    ];
+
+    let a = allocator.allocate(0).unwrap();
+    let b = allocator.allocate(0).unwrap();
+    let c = allocator.allocate(1).unwrap();
+    assert_eq!(allocator.free_frames(), free_before - 4);
+
+    unsafe {
+        allocator.deallocate(b, 0);
+        allocator.deallocate(a, 0);
+        allocator.deallocate(c, 1);
+    }
+
+    assert_eq!(allocator.free_frames(), free_before);
+    for (order, &count) in blocks_before.iter().enumerate() {
+        assert_eq!(allocator.free_blocks(order), count);
+    }
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no 2MiB block available");
+    assert!(frame.start_address().is_aligned(2 * 1024 * 1024u64));
+    assert_eq!(allocator.free_frames(), free_before - 512);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+}
@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    address_space::AddressSpace,
+    cow::COW,
+    vma,
+    vmalloc::{vfree, vmalloc},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Page, PageTableFlags, PhysFrame, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// カーネルが使っていない L4 エントリの範囲にあるアドレス
+const PRIVATE_ADDR: u64 = 0x_6000_0000_0000;
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    memory::with_kernel_memory(|memory| memory.mapper.translate_addr(addr)).unwrap()
+}
+
+fn ref_count(addr: PhysAddr) -> usize {
+    let frame = PhysFrame::containing_address(addr);
+    memory::with_kernel_memory(|memory| memory.frame_allocator.ref_count(frame)).unwrap()
+}
+
+/// 物理メモリのマッピングを通して addr の値を読む
+fn read_phys(addr: PhysAddr) -> u64 {
+    let offset = memory::with_kernel_memory(|memory| memory.mapper.phys_offset()).unwrap();
+    unsafe { (offset + addr.as_u64()).as_ptr::<u64>().read_volatile() }
+}
+
+#[test_case]
+fn write_copies_shared_frame() {
+    let used_before = used_frames();
+    let src = vmalloc(4096, PageTableFlags::WRITABLE).expect("vmalloc failed");
+    let dst = vma::reserve(4096, PageTableFlags::WRITABLE, "cow test").expect("no virtual space");
+    let src_ptr: *mut u64 = src.as_mut_ptr();
+    let dst_ptr: *mut u64 = dst.start.as_mut_ptr();
+
+    unsafe { src_ptr.write_volatile(1) };
+    memory::with_kernel_memory(|memory| {
+        memory.share_cow(
+            Page::containing_address(src),
+            Page::containing_address(dst.start),
+        )
+    })
+    .unwrap()
+    .expect("share failed");
+
+    let shared = translate(src).unwrap();
+    assert_eq!(translate(dst.start), Some(shared));
+    assert_eq!(ref_count(shared), 2);
+    assert_eq!(unsafe { dst_ptr.read_volatile() }, 1);
+
+    // 共有先への書き込みでフレームがコピーされ、共有元には影響しない
+    unsafe { dst_ptr.write_volatile(2) };
+    assert_ne!(translate(dst.start), Some(shared));
+    assert_eq!(unsafe { src_ptr.read_volatile() }, 1);
+    assert_eq!(unsafe { dst_ptr.read_volatile() }, 2);
+    assert_eq!(ref_count(shared), 1);
+
+    // 最後の参照ならコピーせずに書き込み可能に戻る
+    unsafe { src_ptr.write_volatile(3) };
+    assert_eq!(translate(src), Some(shared));
+    assert_eq!(unsafe { src_ptr.read_volatile() }, 3);
+
+    memory::with_kernel_memory(|memory| unsafe { memory.unmap_range(dst.start, dst.size, true) });
+    vma::release(dst.start);
+    unsafe { vfree(src) };
+    // 途中のページテーブルの分は残る
+    assert!(used_frames() - used_before <= 3);
+}
+
+#[test_case]
+fn shared_across_address_spaces() {
+    let frame: PhysFrame =
+        memory::with_kernel_memory(|memory| memory.frame_allocator.allocate_frame())
+            .unwrap()
+            .expect("out of frames");
+    let addr = VirtAddr::new(PRIVATE_ADDR);
+    let page = Page::containing_address(addr);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let mut parent = AddressSpace::new().expect("failed to create address space");
+    let mut child = AddressSpace::new().expect("failed to create address space");
+    parent.map(page, frame, flags).expect("map failed");
+    let previous = unsafe { parent.switch_to() };
+    unsafe { addr.as_mut_ptr::<u64>().write_volatile(7) };
+    unsafe { Cr3::write(previous.0, previous.1) };
+
+    parent.share_cow(page, &mut child).expect("share failed");
+    assert_eq!(child.translate(addr), Some(frame.start_address()));
+    let child_flags = child.walker().translate_addr(addr).unwrap().flags;
+    assert!(child_flags.contains(COW));
+    assert!(!child_flags.contains(PageTableFlags::WRITABLE));
+
+    let previous = unsafe { child.switch_to() };
+    unsafe {
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 7);
+        addr.as_mut_ptr::<u64>().write_volatile(8);
+        assert_eq!(addr.as_ptr::<u64>().read_volatile(), 8);
+    }
+    unsafe { Cr3::write(previous.0, previous.1) };
+
+    let child_frame = child.translate(addr).unwrap();
+    assert_ne!(child_frame, frame.start_address());
+    assert_eq!(read_phys(frame.start_address()), 7);
+    assert_eq!(read_phys(child_frame), 8);
+
+    memory::with_kernel_memory(|memory| unsafe {
+        memory
+            .frame_allocator
+            .deallocate_frame(parent.unmap(page).unwrap());
+        memory
+            .frame_allocator
+            .deallocate_frame(child.unmap(page).unwrap());
+    });
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    demand::{self, ReserveError},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+const REGION_START: u64 = 0x_5555_0000_0000;
+const REGION_SIZE: u64 = 64 * 1024 * 1024;
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+#[test_case]
+fn touched_pages_are_mapped_on_demand() {
+    let start = VirtAddr::new(REGION_START);
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let used_before = used_frames();
+    demand::reserve(start, REGION_SIZE, flags).expect("reserve failed");
+    assert_eq!(used_frames(), used_before);
+
+    // 離れた 3 ページだけに触れる
+    let offsets = [0, REGION_SIZE / 2, REGION_SIZE - 8];
+    for (i, &offset) in offsets.iter().enumerate() {
+        let ptr: *mut u64 = (start + offset).as_mut_ptr();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(i as u64 + 1);
+        }
+    }
+    for (i, &offset) in offsets.iter().enumerate() {
+        let ptr: *const u64 = (start + offset).as_ptr();
+        assert_eq!(unsafe { ptr.read_volatile() }, i as u64 + 1);
+    }
+
+    // 触れたページ分と、途中のページテーブル分しか使わない
+    let used = used_frames() - used_before;
+    assert!(
+        used >= offsets.len() && used <= offsets.len() * 4,
+        "{} frames used",
+        used
+    );
+
+    let region = unsafe { demand::release(start) }.expect("region not registered");
+    assert_eq!(region.size, REGION_SIZE);
+    assert!(demand::region_containing(start).is_none());
+    // ページテーブル自体は残る
+    assert!(used_frames() - used_before <= offsets.len() * 3);
+}
+
+#[test_case]
+fn overlapping_reservation_is_rejected() {
+    let start = VirtAddr::new(REGION_START);
+    let flags = PageTableFlags::WRITABLE;
+
+    demand::reserve(start, REGION_SIZE, flags).expect("reserve failed");
+    assert_eq!(
+        demand::reserve(start + REGION_SIZE / 2, REGION_SIZE, flags),
+        Err(ReserveError::Overlap)
+    );
+    assert_eq!(
+        demand::reserve(start + (REGION_SIZE + 1), 4096, flags),
+        Err(ReserveError::Unaligned)
+    );
+    unsafe { demand::release(start) };
+}
@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::bitmap::BitmapFrameAllocator;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn counts_are_consistent() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    assert!(allocator.free_frames() > 0);
+    assert_eq!(
+        allocator.free_frames() + allocator.used_frames(),
+        allocator.total_frames()
+    );
+}
+
+#[test_case]
+fn allocate_and_deallocate() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+
+    let frame: PhysFrame = allocator.allocate_frame().expect("out of frames");
+    assert_eq!(allocator.free_frames(), free_before - 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let first: PhysFrame = allocator.allocate_frame().unwrap();
+    let second = allocator.allocate_frame().unwrap();
+    assert_ne!(first, second);
+
+    unsafe { allocator.deallocate_frame(first) };
+    assert_eq!(allocator.allocate_frame(), Some(first));
+
+    unsafe {
+        allocator.deallocate_frame(first);
+        allocator.deallocate_frame(second);
+    }
+}
+
+#[test_case]
+fn allocate_aligned_2mib_frame() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free_before = allocator.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().expect("no free 2MiB frame");
+    assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(allocator.free_frames(), free_before - 512);
+
+    // 範囲内の 4KiB フレームは払い出されない
+    let small: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    let huge_range = frame.start_address()..frame.start_address() + Size2MiB::SIZE;
+    assert!(!huge_range.contains(&small.start_address()));
+
+    unsafe {
+        allocator.deallocate_frame(small);
+        allocator.deallocate_frame(frame);
+    }
+    assert_eq!(allocator.free_frames(), free_before);
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+
+use blog_os::{
+    exit_qemu, memory::bitmap::BitmapFrameAllocator, serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{
+    bootinfo::{MemoryMap, MemoryRegionType},
+    entry_point, BootInfo,
+};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{FrameDeallocator, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("frame_reserved_free::reserved_frame_free_is_rejected...\t");
+
+    blog_os::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+
+    let frame = reserved_frame(&boot_info.memory_map);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+
+    serial_println!("[failed]\nFreeing a reserved frame was not detected");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "deallocating reserved frame")
+}
+
+/// usable な領域に挟まれた、usable でない領域の先頭フレームを返す
+///
+/// カーネルのイメージなどがこれにあたり、ビットマップの管理範囲に含まれる。
+fn reserved_frame(memory_map: &MemoryMap) -> PhysFrame {
+    let usable = || {
+        memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+    };
+    let start = usable().map(|r| r.range.start_addr()).min().unwrap();
+    let end = usable().map(|r| r.range.end_addr()).max().unwrap();
+
+    let region = memory_map
+        .iter()
+        .find(|r| {
+            r.region_type != MemoryRegionType::Usable
+                && r.range.start_addr() > start
+                && r.range.end_addr() < end
+        })
+        .expect("no reserved region inside the usable range");
+    PhysFrame::containing_address(PhysAddr::new(region.range.start_addr()))
+}
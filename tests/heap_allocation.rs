@@ -6,24 +6,25 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use blog_os::{
     allocator,
-    memory::{self, BootInfoFrameAllocator},
+    memory::{self, walk::PageTableWalker},
+    serial_print,
 };
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use x86_64::VirtAddr;
+use x86_64::{
+    structures::paging::{PageSize, Size2MiB},
+    VirtAddr,
+};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     blog_os::init();
 
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    blog_os::init_memory(boot_info);
 
     test_main();
 
@@ -65,6 +66,8 @@ fn many_boxes() {
     }
 }
 
+// NOTE: バンプアロケータは生存中の割り当てが一つでもあると領域を再利用できないため、このテストは必ず失敗する
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     let long_lived = Box::new(1);
@@ -75,3 +78,122 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+#[test_case]
+fn heap_grows_on_demand() {
+    use blog_os::allocator::{heap_size, HEAP_SIZE};
+    let vec = vec![1u8; HEAP_SIZE * 2];
+    assert_eq!(
+        vec.iter().map(|&x| x as usize).sum::<usize>(),
+        HEAP_SIZE * 2
+    );
+    assert!(heap_size() > HEAP_SIZE);
+}
+
+#[test_case]
+fn grown_heap_uses_huge_pages() {
+    use blog_os::allocator::HEAP_START;
+    const SIZE: usize = 3 * 1024 * 1024;
+
+    // 2MiB 境界まで拡張した後は、2MiB のページでマップされる
+    let vec = vec![1u8; SIZE];
+    assert_eq!(vec[SIZE - 1], 1);
+
+    let offset = memory::with_kernel_memory(|memory| memory.mapper.phys_offset()).unwrap();
+    let walker = unsafe { PageTableWalker::active(offset) };
+    let addr = VirtAddr::new((HEAP_START + Size2MiB::SIZE as usize) as u64);
+    let translation = walker.translate_addr(addr).unwrap();
+    assert_eq!(translation.page_size, Size2MiB::SIZE);
+}
+
+#[test_case]
+fn heap_range_is_recorded() {
+    use blog_os::allocator::{HEAP_MAX_SIZE, HEAP_OWNER, HEAP_START};
+    use blog_os::memory::vma;
+
+    // 拡張できる範囲の末尾まで、ヒープの範囲として記録されている
+    let last = VirtAddr::new((HEAP_START + HEAP_MAX_SIZE - 1) as u64);
+    let area = vma::find(last).expect("heap range not recorded");
+    assert_eq!(area.owner, HEAP_OWNER);
+    assert_eq!(area.start.as_u64(), HEAP_START as u64);
+    assert_eq!(allocator::stats().blocked_grows, 0);
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = allocator::stats();
+
+    let x = Box::new([0u64; 16]);
+    let during = allocator::stats();
+    assert_eq!(during.live_bytes, before.live_bytes + 128);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak_bytes >= during.live_bytes);
+
+    drop(x);
+    let after = allocator::stats();
+    assert_eq!(after.live_bytes, before.live_bytes);
+    assert_eq!(after.frees, before.frees + 1);
+}
+
+#[test_case]
+fn no_leaks_in_vec_workload() {
+    allocator::assert_no_leaks(|| {
+        let mut vec = Vec::new();
+        for i in 0..1000 {
+            vec.push(Box::new(i));
+        }
+        assert_eq!(vec.len(), 1000);
+    });
+}
+
+#[test_case]
+fn vec_growth_copies_avoided() {
+    let before = allocator::stats();
+
+    let mut vec = Vec::new();
+    for i in 0..(64 * 1024 / 8) {
+        vec.push(i as u64);
+    }
+    vec.shrink_to_fit();
+
+    let after = allocator::stats();
+    let reallocations = after.reallocations - before.reallocations;
+    let in_place = after.in_place_reallocations - before.in_place_reallocations;
+    serial_print!(
+        "{} of {} reallocations done in place... ",
+        in_place,
+        reallocations
+    );
+
+    assert!(reallocations > 0);
+    #[cfg(all(
+        any(
+            feature = "alloc-linked-list",
+            feature = "alloc-buddy",
+            feature = "alloc-tlsf"
+        ),
+        not(feature = "debug-heap")
+    ))]
+    assert!(in_place > 0);
+    assert_eq!(vec.iter().sum::<u64>(), (8191 * 8192) / 2);
+}
+
+// NOTE: debug-heap はガード領域を付け直すため、常にコピーして再割り当てする
+#[cfg(all(feature = "alloc-fixed-block", not(feature = "debug-heap")))]
+#[test_case]
+fn realloc_within_size_class() {
+    let before = allocator::stats();
+
+    // 9..=16 バイトは同じサイズクラスなので、すべてその場で伸長できる
+    let mut vec: Vec<u8> = Vec::with_capacity(9);
+    for len in 10..=16 {
+        vec.reserve_exact(len);
+    }
+    assert_eq!(vec.capacity(), 16);
+
+    let after = allocator::stats();
+    assert_eq!(
+        after.in_place_reallocations - before.in_place_reallocations,
+        7
+    );
+}
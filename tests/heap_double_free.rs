@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    double_free_is_detected();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "double free")
+}
+
+fn double_free_is_detected() {
+    serial_print!("heap_double_free::double_free_is_detected...\t");
+
+    let layout = Layout::new::<[u64; 4]>();
+    unsafe {
+        let ptr = alloc(layout);
+        assert!(!ptr.is_null());
+        dealloc(ptr, layout);
+        // まだ隔離されているので、ヘッダの印から二重解放とわかる
+        dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::{mem, panic::PanicInfo};
+
+use blog_os::{exit_qemu, serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
+
+/// 実行しようとしたヒープのアドレス
+static mut CODE_ADDR: u64 = 0;
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let fault_addr = Cr2::read();
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && fault_addr.as_u64() == unsafe { CODE_ADDR }
+    {
+        serial_println!("[ok]");
+        exit_qemu(blog_os::QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!(
+            "unexpected page fault at {:?}: {:?}",
+            fault_addr,
+            error_code
+        );
+        exit_qemu(blog_os::QemuExitCode::Failed);
+    }
+    loop {}
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("heap_execution::jump_into_heap...\t");
+
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    TEST_IDT.load();
+
+    // ret 命令だけの関数をヒープに置いて呼び出す
+    let code = Box::new([0xc3u8; 16]);
+    unsafe {
+        CODE_ADDR = code.as_ptr() as u64;
+        let function: extern "C" fn() = mem::transmute(code.as_ptr());
+        function();
+    }
+
+    serial_println!("[failed]");
+    serial_println!("Execution continued after jumping into the heap");
+    exit_qemu(blog_os::QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
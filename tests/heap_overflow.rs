@@ -0,0 +1,41 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    overflow_is_detected();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "heap buffer overflow")
+}
+
+fn overflow_is_detected() {
+    serial_print!("heap_overflow::overflow_is_detected...\t");
+
+    let buffer = Box::new([0u8; 16]);
+    let ptr = Box::into_raw(buffer) as *mut u8;
+
+    // 割り当て範囲の直後に書き込み、解放時にガードバイトの破壊として検出させる
+    unsafe {
+        ptr.add(16).write_volatile(0);
+        drop(Box::from_raw(ptr as *mut [u8; 16]));
+    }
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use blog_os::{
+    allocator::debug::QUARANTINE_LEN, exit_qemu, serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    write_after_free_is_detected();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "use after free detected")
+}
+
+fn write_after_free_is_detected() {
+    serial_print!("heap_use_after_free::write_after_free_is_detected...\t");
+
+    let ptr = Box::into_raw(Box::new([0u8; 32])) as *mut u8;
+    unsafe {
+        drop(Box::from_raw(ptr as *mut [u8; 32]));
+        // 解放済みのブロックに書き込み、ポイズン値を壊す
+        ptr.add(8).write_volatile(0);
+    }
+
+    // 隔離領域から押し出されるときに検出される
+    for i in 0..QUARANTINE_LEN {
+        drop(Box::new(i));
+    }
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    stack::{allocate_stack, is_guard_page},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+    blog_os::gdt::init_stacks();
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_kernel_memory(|memory| memory.mapper.translate_addr(addr))
+        .unwrap()
+        .is_some()
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+#[test_case]
+fn stack_has_unmapped_guard_page() {
+    let used_before = used_frames();
+    let stack = allocate_stack(3 * 4096 + 1).expect("failed to allocate stack");
+    assert_eq!(stack.size(), 4 * 4096);
+    assert_eq!(stack.bottom(), stack.guard_page().start_address() + 4096u64);
+
+    let guard = stack.guard_page().start_address();
+    assert!(!is_mapped(guard));
+    assert!(is_guard_page(guard + 8u64));
+    assert!(!is_guard_page(stack.bottom()));
+
+    // 上端から下端まで書き込める
+    unsafe {
+        let top: *mut u64 = (stack.top() - 8u64).as_mut_ptr();
+        let bottom: *mut u64 = stack.bottom().as_mut_ptr();
+        top.write_volatile(1);
+        bottom.write_volatile(2);
+        assert_eq!(top.read_volatile(), 1);
+        assert_eq!(bottom.read_volatile(), 2);
+    }
+
+    let bottom = stack.bottom();
+    drop(stack);
+    assert!(!is_mapped(bottom));
+    assert!(!is_guard_page(guard));
+    // 途中のページテーブルの分は残る
+    assert!(used_frames() - used_before <= 3);
+}
+
+#[test_case]
+fn stacks_do_not_share_guard_pages() {
+    let a = allocate_stack(4096).expect("failed to allocate stack");
+    let b = allocate_stack(4096).expect("failed to allocate stack");
+
+    // 上のスタックが溢れると、下のスタックに届く前に自身のガードページに当たる
+    let (lower, upper) = if a.top() < b.top() {
+        (&a, &b)
+    } else {
+        (&b, &a)
+    };
+    assert!(lower.top() <= upper.guard_page().start_address());
+    assert!(is_guard_page(upper.bottom() - 1u64));
+}
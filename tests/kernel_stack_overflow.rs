@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, panic::PanicInfo};
+
+use blog_os::{
+    exit_qemu, memory::stack::allocate_stack, serial_print, serial_println, QemuExitCode,
+};
+use bootloader::{entry_point, BootInfo};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("kernel_stack_overflow::overflow_hits_guard_page...\t");
+
+    // カーネルの IDT をそのまま使い、二重フォールトハンドラの報告を確かめる
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+    blog_os::gdt::init_stacks();
+
+    let stack = allocate_stack(4 * 4096).expect("failed to allocate stack");
+    let top = stack.leak();
+
+    // rsp を確保したスタックに切り替えて溢れさせる
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) top.as_u64(),
+            entry = in(reg) run_on_stack as extern "C" fn() -> !,
+            options(noreturn)
+        )
+    }
+}
+
+extern "C" fn run_on_stack() -> ! {
+    stack_overflow();
+    serial_println!("[failed]\nExecution continued after stack overflow");
+    exit_qemu(QemuExitCode::Failed);
+    blog_os::hlt_loop();
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read(); // 末尾最適化を防ぐ
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::expect_panic_handler(info, "kernel stack overflow")
+}
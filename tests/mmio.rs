@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::memory::{
+    self,
+    mmio::{map_mmio, map_mmio_with, CachePolicy, MMIO_OWNER},
+    vma,
+};
+use bootloader::{entry_point, BootInfo};
+use core::{arch::x86_64::__cpuid, panic::PanicInfo};
+use x86_64::{
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// VGA テキストバッファ。ブートローダによって同じ仮想アドレスにもマップされている
+const VGA_BUFFER: u64 = 0xb8000;
+const VGA_BUFFER_SIZE: usize = 80 * 25 * 2;
+
+fn translate(addr: VirtAddr) -> TranslateResult {
+    memory::with_kernel_memory(|memory| memory.mapper.translate(addr)).unwrap()
+}
+
+#[test_case]
+fn uncached_mapping_of_vga_buffer() {
+    // ページ境界に揃っていないアドレスから始める
+    let region =
+        map_mmio(PhysAddr::new(VGA_BUFFER + 160), VGA_BUFFER_SIZE - 160).expect("map_mmio failed");
+    let virt = region.virt_addr();
+    assert_eq!(virt.as_u64() % 4096, 160);
+
+    match translate(virt) {
+        TranslateResult::Mapped { flags, .. } => {
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH))
+        }
+        other => panic!("not mapped: {:?}", other),
+    }
+    assert_eq!(vma::find(virt).map(|vma| vma.owner), Some(MMIO_OWNER));
+
+    // 2 行目の先頭に 'M' を書き、元のマッピングから読めることを確かめる
+    let cell = 0x0f00 | u16::from(b'M');
+    region.write::<u16>(0, cell);
+    assert_eq!(region.read::<u16>(0), cell);
+    let vga = VGA_BUFFER as *const u16;
+    assert_eq!(unsafe { vga.add(80).read_volatile() }, cell);
+
+    drop(region);
+    assert!(matches!(translate(virt), TranslateResult::NotMapped));
+    assert!(vma::find(virt).is_none());
+}
+
+#[test_case]
+fn write_combining_mapping_can_be_dropped() {
+    let region = map_mmio_with(
+        PhysAddr::new(VGA_BUFFER),
+        VGA_BUFFER_SIZE,
+        CachePolicy::WriteCombining,
+    )
+    .expect("map_mmio_with failed");
+    let virt = region.virt_addr();
+    region.write::<u16>(2, 0x0f00 | u16::from(b'W'));
+
+    // PAT に対応していれば、4KiB ページの PAT ビット (HUGE_PAGE と同じ位置) で書き込み結合を選ぶ
+    let has_pat = __cpuid(1).edx & (1 << 16) != 0;
+    match translate(virt) {
+        TranslateResult::Mapped { flags, .. } if has_pat => {
+            assert!(flags.contains(PageTableFlags::HUGE_PAGE));
+            assert!(!flags.contains(PageTableFlags::NO_CACHE));
+        }
+        TranslateResult::Mapped { flags, .. } => assert!(flags.contains(PageTableFlags::NO_CACHE)),
+        other => panic!("not mapped: {:?}", other),
+    }
+
+    drop(region);
+    assert!(matches!(translate(virt), TranslateResult::NotMapped));
+}
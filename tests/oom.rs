@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(allocator_api)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+// バンプアロケータではテストを行わない
+#![cfg_attr(feature = "alloc-bump", allow(unused))]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use blog_os::{
+    allocator::{
+        self,
+        oom::{self, OomPolicy},
+        HEAP_MAX_SIZE,
+    },
+    task::{
+        executor::{Executor, SpawnError},
+        Task,
+    },
+};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    future,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+// バンプアロケータは解放しても再利用しないので、回復を確かめられない
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn refuses_new_work_until_memory_is_freed() {
+    // ヒープを使い切る前に必要なものを確保しておく
+    let mut executor = Executor::new();
+    let task = Task::new(async {});
+    let mut boxes = Vec::with_capacity(1024);
+
+    oom::set_policy(OomPolicy::RefuseNewWork);
+    allocator::set_heap_limit(allocator::heap_size());
+
+    // 予備領域も使い切るまで割り当てる
+    while boxes.len() < boxes.capacity() {
+        match Box::try_new([0u8; 4096]) {
+            Ok(block) => boxes.push(block),
+            Err(_) => break,
+        }
+    }
+    assert!(boxes.len() < boxes.capacity(), "heap was not exhausted");
+    assert!(oom::under_pressure());
+
+    let buffer = [0u8; 4096];
+    assert!(Task::try_new(async move {
+        let _ = &buffer;
+    })
+    .is_err());
+    let task = match executor.try_spawn(task) {
+        Err(SpawnError::OutOfMemory(task)) => task,
+        other => panic!("expected OutOfMemory, got {:?}", other),
+    };
+
+    drop(boxes);
+    assert!(!oom::under_pressure());
+    executor
+        .try_spawn(task)
+        .expect("spawn failed after freeing memory");
+
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+    oom::set_policy(OomPolicy::Panic);
+}
+
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn try_spawn_fails_without_memory_under_panic_policy() {
+    // 既定の Panic ポリシーでも、タスクを格納できなければ Err を返す
+    assert_eq!(oom::policy(), OomPolicy::Panic);
+    let mut executor = Executor::new();
+    let task = Task::new(async {});
+    let mut boxes = Vec::with_capacity(1024);
+    let mut small = Vec::with_capacity(4096);
+
+    allocator::set_heap_limit(allocator::heap_size());
+    while boxes.len() < boxes.capacity() {
+        match Box::try_new([0u8; 4096]) {
+            Ok(block) => boxes.push(block),
+            Err(_) => break,
+        }
+    }
+    assert!(boxes.len() < boxes.capacity(), "heap was not exhausted");
+    // 小さな割り当ても通らなくなるまで埋める
+    while small.len() < small.capacity() {
+        match Box::try_new(0u64) {
+            Ok(block) => small.push(block),
+            Err(_) => break,
+        }
+    }
+
+    let task = match executor.try_spawn(task) {
+        Err(SpawnError::OutOfMemory(task)) => task,
+        other => panic!("expected OutOfMemory, got {:?}", other),
+    };
+
+    drop(small);
+    drop(boxes);
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+    executor
+        .try_spawn(task)
+        .expect("spawn failed after freeing memory");
+}
+
+static HOG_DROPPED: AtomicBool = AtomicBool::new(false);
+
+struct DropFlag;
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        HOG_DROPPED.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(not(feature = "alloc-bump"))]
+#[test_case]
+fn kills_largest_task() {
+    let mut executor = Executor::new();
+    let mut boxes = Vec::with_capacity(1024);
+
+    oom::set_policy(OomPolicy::KillLargestTask);
+    // 64KiB を持ったまま終わらないタスク
+    executor.spawn(Task::new(async {
+        let _flag = DropFlag;
+        let _hog = Vec::<u8>::with_capacity(64 * 1024);
+        future::pending::<()>().await;
+    }));
+    executor.run_ready_tasks();
+    assert!(!HOG_DROPPED.load(Ordering::SeqCst));
+
+    // 次の実行で終了要求が処理されるよう、小さなタスクを入れておく
+    executor.spawn(Task::new(async {}));
+
+    allocator::set_heap_limit(allocator::heap_size());
+    while boxes.len() < boxes.capacity() {
+        match Box::try_new([0u8; 4096]) {
+            Ok(block) => boxes.push(block),
+            Err(_) => break,
+        }
+    }
+    assert!(oom::under_pressure());
+
+    executor.run_ready_tasks();
+    assert!(HOG_DROPPED.load(Ordering::SeqCst));
+
+    drop(boxes);
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+    oom::set_policy(OomPolicy::Panic);
+}
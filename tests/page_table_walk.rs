@@ -0,0 +1,148 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use blog_os::memory::{
+    self,
+    address_space::AddressSpace,
+    walk::{MappedRange, PageTableWalker, TranslateError},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame, Translate},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+/// カーネルが使っていない L4 エントリの範囲にあるアドレス
+const PRIVATE_ADDR: u64 = 0x_6000_0000_0000;
+/// L4 エントリ 1 つが指す大きさ
+const L4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+/// マップしても実害のない物理アドレス (VGA テキストバッファ)
+const VGA_BUFFER: u64 = 0xb8000;
+
+const FLAGS: PageTableFlags = PageTableFlags::PRESENT;
+
+fn page(index: u64) -> Page {
+    Page::containing_address(VirtAddr::new(PRIVATE_ADDR + index * 4096))
+}
+
+fn frame(index: u64) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(VGA_BUFFER + index * 4096))
+}
+
+fn private_ranges(space: &AddressSpace) -> Vec<MappedRange> {
+    space
+        .walker()
+        .ranges()
+        .filter(|range| {
+            let start = range.start.as_u64();
+            PRIVATE_ADDR <= start && start < PRIVATE_ADDR + L4_ENTRY_SIZE
+        })
+        .collect()
+}
+
+#[test_case]
+fn contiguous_pages_are_coalesced() {
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    // 物理アドレスも連続する 3 ページと、先頭のフレームに戻る 1 ページ
+    for i in 0..3 {
+        space.map(page(i), frame(i), FLAGS).unwrap();
+    }
+    space.map(page(3), frame(0), FLAGS).unwrap();
+
+    let ranges = private_ranges(&space);
+    assert_eq!(ranges.len(), 2);
+    assert_eq!(ranges[0].start, page(0).start_address());
+    assert_eq!(ranges[0].size, 3 * 4096);
+    assert_eq!(ranges[0].phys, frame(0).start_address());
+    assert_eq!(ranges[0].page_size, 4096);
+    assert_eq!(ranges[1].start, page(3).start_address());
+    assert_eq!(ranges[1].size, 4096);
+
+    // フラグが異なれば別の範囲になる
+    space.unmap(page(1)).unwrap();
+    space
+        .map(page(1), frame(1), FLAGS | PageTableFlags::NO_EXECUTE)
+        .unwrap();
+    assert_eq!(private_ranges(&space).len(), 4);
+
+    for i in 0..4 {
+        space.unmap(page(i)).unwrap();
+    }
+    assert!(private_ranges(&space).is_empty());
+}
+
+#[test_case]
+fn translate_reports_failing_level() {
+    let mut space = AddressSpace::new().expect("failed to create address space");
+    let walker = space.walker();
+    assert_eq!(
+        walker.translate_addr(page(0).start_address()),
+        Err(TranslateError::NotPresent { level: 4 })
+    );
+
+    space.map(page(0), frame(0), FLAGS).unwrap();
+    let walker = space.walker();
+    let translation = walker
+        .translate_addr(page(0).start_address() + 0x123u64)
+        .unwrap();
+    assert_eq!(translation.phys, frame(0).start_address() + 0x123u64);
+    assert_eq!(translation.page_size, 4096);
+
+    let base = VirtAddr::new(PRIVATE_ADDR);
+    let cases = [
+        (base + 4096u64, 1),
+        (base + 2 * 1024 * 1024u64, 2),
+        (base + 1024 * 1024 * 1024u64, 3),
+    ];
+    for &(addr, level) in cases.iter() {
+        assert_eq!(
+            walker.translate_addr(addr),
+            Err(TranslateError::NotPresent { level })
+        );
+    }
+
+    space.unmap(page(0)).unwrap();
+}
+
+#[test_case]
+fn translate_matches_kernel_mapper() {
+    let heap_value = Box::new(41);
+    let addr = VirtAddr::from_ptr(&*heap_value);
+
+    let (offset, expected) = memory::with_kernel_memory(|memory| {
+        (
+            memory.mapper.phys_offset(),
+            memory.mapper.translate_addr(addr),
+        )
+    })
+    .unwrap();
+    let walker = unsafe { PageTableWalker::active(offset) };
+    let translation = walker.translate_addr(addr).unwrap();
+    assert_eq!(Some(translation.phys), expected);
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+    assert!(translation.flags.contains(PageTableFlags::NO_EXECUTE));
+}
@@ -0,0 +1,128 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use blog_os::allocator::{self, slab::SlabCache};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+#[derive(Debug, PartialEq)]
+struct Object {
+    id: u64,
+    payload: [u8; 40],
+}
+
+static OBJECT_CACHE: SlabCache<Object> = SlabCache::new("object");
+
+#[test_case]
+fn alloc_and_drop() {
+    let mut object = OBJECT_CACHE
+        .alloc(Object {
+            id: 1,
+            payload: [0; 40],
+        })
+        .expect("slab allocation failed");
+    object.payload[0] = 42;
+
+    assert_eq!(object.id, 1);
+    assert_eq!(object.payload[0], 42);
+    assert_eq!(OBJECT_CACHE.stats().allocated, 1);
+
+    drop(object);
+    assert_eq!(OBJECT_CACHE.stats().allocated, 0);
+}
+
+#[test_case]
+fn objects_span_multiple_slabs() {
+    let per_slab = OBJECT_CACHE.stats().objects_per_slab;
+    let count = per_slab * 3 + 1;
+
+    let objects: Vec<_> = (0..count as u64)
+        .map(|id| {
+            OBJECT_CACHE
+                .alloc(Object {
+                    id,
+                    payload: [id as u8; 40],
+                })
+                .unwrap()
+        })
+        .collect();
+
+    assert_eq!(OBJECT_CACHE.stats().slabs, 4);
+    for (id, object) in objects.iter().enumerate() {
+        assert_eq!(object.id, id as u64);
+        assert!(object.payload.iter().all(|&b| b == id as u8));
+    }
+}
+
+#[test_case]
+fn empty_slabs_are_returned() {
+    allocator::assert_no_leaks(|| {
+        let objects: Vec<_> = (0..100)
+            .map(|id| {
+                OBJECT_CACHE
+                    .alloc(Object {
+                        id,
+                        payload: [0; 40],
+                    })
+                    .unwrap()
+            })
+            .collect();
+        assert!(OBJECT_CACHE.stats().slabs > 0);
+
+        drop(objects);
+        // 一つだけ次の割り当てに備えて残す
+        assert_eq!(OBJECT_CACHE.stats().slabs, 1);
+        assert_eq!(OBJECT_CACHE.stats().empty_slabs, 1);
+
+        OBJECT_CACHE.shrink();
+        assert_eq!(OBJECT_CACHE.stats().slabs, 0);
+    });
+}
+
+#[test_case]
+fn alternating_alloc_and_free_keeps_slab() {
+    let object = OBJECT_CACHE.alloc(Object {
+        id: 0,
+        payload: [0; 40],
+    });
+    drop(object);
+
+    // 空のスラブを使い回すので、グローバルアロケータからは割り当てない
+    let allocations = allocator::stats().allocations;
+    for id in 0..100 {
+        let object = OBJECT_CACHE
+            .alloc(Object {
+                id,
+                payload: [0; 40],
+            })
+            .unwrap();
+        assert_eq!(object.id, id);
+    }
+    assert_eq!(allocator::stats().allocations, allocations);
+    assert_eq!(OBJECT_CACHE.stats().slabs, 1);
+
+    OBJECT_CACHE.shrink();
+}
@@ -4,7 +4,12 @@
 
 use core::panic::PanicInfo;
 
-use blog_os::{exit_qemu, serial_print, serial_println};
+use blog_os::{
+    exit_qemu,
+    memory::{self},
+    serial_print, serial_println,
+};
+use bootloader::{entry_point, BootInfo};
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -33,11 +38,18 @@ extern "x86-interrupt" fn test_double_fault_handler(
     loop {}
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
+    memory::protect::enable_nx();
     blog_os::gdt::init();
+
+    // 二重フォールト用のスタックはカーネルのメモリから確保する
+    blog_os::init_memory(boot_info);
+    blog_os::gdt::init_stacks();
+
     init_test_idt();
 
     stack_overflow();
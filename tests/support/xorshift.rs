@@ -0,0 +1,27 @@
+//! アロケータのテストで使う決定的な疑似乱数
+//!
+//! カーネルには含めず、統合テストと host_tests の両方から `#[path]` で読み込んで使う。
+
+/// xorshift64*
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // 0 を状態にすると 0 しか出力しなくなる
+        XorShift(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// [0, n) の値を返す
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
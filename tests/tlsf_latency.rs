@@ -0,0 +1,163 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[path = "support/xorshift.rs"]
+mod xorshift;
+
+use blog_os::{
+    allocator::{linked_list::LinkedListAllocator, tlsf::TlsfAllocator, Locked},
+    serial_print,
+};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::x86_64::_rdtsc,
+    panic::PanicInfo,
+    ptr,
+};
+use x86_64::instructions::interrupts;
+use xorshift::XorShift;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+const ARENA_SIZE: usize = 1024 * 1024;
+const BLOCK_COUNT: usize = 1024;
+const ROUNDS: usize = 4096;
+/// 計測を繰り返す回数。各回の値の中央値で比較する
+const RUNS: usize = 5;
+
+#[repr(align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+/// 割り当てにかかったサイクル数
+#[derive(Debug, Clone, Copy)]
+struct Latency {
+    /// 99 パーセンタイル。まれに入るホスト側の揺らぎを除いた最悪に近い値
+    p99: u64,
+    max: u64,
+}
+
+/// f の実行にかかったサイクル数を、割り込みを止めた状態で測る
+fn measure(f: impl FnOnce()) -> u64 {
+    interrupts::without_interrupts(|| {
+        let start = unsafe { _rdtsc() };
+        f();
+        unsafe { _rdtsc() }.saturating_sub(start)
+    })
+}
+
+/// 小さなブロックを交互に解放してヒープを断片化させたあと、
+/// ランダムな割り当てと解放を繰り返して割り当てのサイクル数を測る
+fn fragmenting_workload<A: GlobalAlloc>(allocator: &A) -> Latency {
+    let mut rng = XorShift::new(0x2545_F491_4F6C_DD1D);
+    let mut blocks = [(ptr::null_mut(), Layout::new::<u8>()); BLOCK_COUNT];
+    let mut samples = [0u64; ROUNDS];
+    let mut sample_count = 0;
+
+    for block in blocks.iter_mut() {
+        let layout = Layout::from_size_align(16 + rng.below(240), 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        assert!(!ptr.is_null());
+        *block = (ptr, layout);
+    }
+    for block in blocks.iter_mut().step_by(2) {
+        unsafe { allocator.dealloc(block.0, block.1) };
+        block.0 = ptr::null_mut();
+    }
+
+    for _ in 0..ROUNDS {
+        let block = &mut blocks[rng.below(BLOCK_COUNT)];
+        if block.0.is_null() {
+            // 穴に収まらない大きさも混ぜ、空きリストの探索が長くなるようにする
+            let size = match rng.below(8) {
+                0 => 1024 + rng.below(3072),
+                _ => 16 + rng.below(240),
+            };
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let mut ptr = ptr::null_mut();
+            samples[sample_count] = measure(|| ptr = unsafe { allocator.alloc(layout) });
+            sample_count += 1;
+            assert!(!ptr.is_null());
+            *block = (ptr, layout);
+        } else {
+            let (ptr, layout) = *block;
+            unsafe { allocator.dealloc(ptr, layout) };
+            block.0 = ptr::null_mut();
+        }
+    }
+
+    for &(ptr, layout) in blocks.iter().filter(|block| !block.0.is_null()) {
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+
+    let samples = &mut samples[..sample_count];
+    samples.sort_unstable();
+    Latency {
+        p99: samples[samples.len() * 99 / 100],
+        max: samples[samples.len() - 1],
+    }
+}
+
+fn median(values: &mut [u64]) -> u64 {
+    values.sort_unstable();
+    values[values.len() / 2]
+}
+
+#[test_case]
+fn tlsf_worst_case_beats_linked_list() {
+    let arena = unsafe { ptr::addr_of_mut!(ARENA.0) as usize };
+    let mut tlsf_p99 = [0; RUNS];
+    let mut linked_list_p99 = [0; RUNS];
+    let mut tlsf_max = 0;
+    let mut linked_list_max = 0;
+
+    for run in 0..RUNS {
+        let linked_list = Locked::new(LinkedListAllocator::new());
+        unsafe { linked_list.lock().init(arena, ARENA_SIZE) };
+        let latency = fragmenting_workload(&linked_list);
+        linked_list_p99[run] = latency.p99;
+        linked_list_max = linked_list_max.max(latency.max);
+
+        let tlsf = Locked::new(TlsfAllocator::new());
+        unsafe { tlsf.lock().init(arena, ARENA_SIZE) };
+        let latency = fragmenting_workload(&tlsf);
+        tlsf_p99[run] = latency.p99;
+        tlsf_max = tlsf_max.max(latency.max);
+    }
+
+    let tlsf_p99 = median(&mut tlsf_p99);
+    let linked_list_p99 = median(&mut linked_list_p99);
+    serial_print!(
+        "p99 tlsf {} / linked list {} cycles (max {} / {})... ",
+        tlsf_p99,
+        linked_list_p99,
+        tlsf_max,
+        linked_list_max
+    );
+
+    // 連結リストは大きな要求のたびに数百の穴を順に辿るが、TLSF はビットマップで空きリストを選ぶので
+    // 穴の数によらず一定の手順で終わる。1 回の最大値はぶれるので、繰り返した p99 の中央値で比べる
+    assert!(
+        tlsf_p99 < linked_list_p99,
+        "TLSF p99 allocation latency {} is not below linked list {}",
+        tlsf_p99,
+        linked_list_p99
+    );
+}
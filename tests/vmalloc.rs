@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use blog_os::{
+    allocator,
+    memory::{
+        self, vma,
+        vmalloc::{vfree, vmalloc, VMALLOC_OWNER},
+    },
+};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, slice};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        PageTableFlags, Translate,
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::init();
+
+    blog_os::init_memory(boot_info);
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+const FLAGS: PageTableFlags = PageTableFlags::WRITABLE;
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+#[test_case]
+fn large_buffer_outside_heap() {
+    // ヒープの初期サイズより大きなバッファ
+    const SIZE: usize = 4 * 1024 * 1024;
+    let heap_size = allocator::heap_size();
+
+    let addr = vmalloc(SIZE, FLAGS).expect("vmalloc failed");
+    let buffer = unsafe { slice::from_raw_parts_mut(addr.as_mut_ptr::<u8>(), SIZE) };
+    assert!(buffer.iter().all(|&b| b == 0));
+    for (i, b) in buffer.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    assert_eq!(buffer[SIZE - 1], (SIZE - 1) as u8);
+
+    let area = vma::find(addr + (SIZE - 1)).expect("area not recorded");
+    assert_eq!(area.owner, VMALLOC_OWNER);
+    assert_eq!(area.size, SIZE as u64);
+    assert_eq!(allocator::heap_size(), heap_size);
+
+    unsafe { vfree(addr) };
+    assert!(vma::find(addr).is_none());
+}
+
+#[test_case]
+fn areas_do_not_overlap_and_frames_are_returned() {
+    let used_before = used_frames();
+
+    let a = vmalloc(4096, FLAGS).expect("vmalloc failed");
+    let b = vmalloc(3 * 4096, FLAGS).expect("vmalloc failed");
+    assert!(b >= a + 4096u64 || a >= b + 3 * 4096u64);
+
+    unsafe {
+        a.as_mut_ptr::<u64>().write_volatile(1);
+        b.as_mut_ptr::<u64>().write_volatile(2);
+        assert_eq!(a.as_ptr::<u64>().read_volatile(), 1);
+        vfree(a);
+        vfree(b);
+    }
+
+    // 途中のページテーブルの分は残る
+    assert!(used_frames() - used_before <= 3);
+}
+
+#[test_case]
+fn large_buffer_uses_2mib_pages() {
+    const SIZE: usize = 4 * 1024 * 1024;
+    let used_before = used_frames();
+
+    let addr = vmalloc(SIZE, FLAGS).expect("vmalloc failed");
+    assert!(addr.is_aligned(2 * 1024 * 1024u64));
+    let translate = |addr: VirtAddr| {
+        memory::with_kernel_memory(|memory| memory.mapper.translate(addr)).unwrap()
+    };
+    for offset in [0, SIZE / 2] {
+        match translate(addr + offset) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {}
+            other => panic!("expected a 2MiB page at offset {:#x}: {:?}", offset, other),
+        }
+    }
+
+    unsafe { vfree(addr) };
+    // 途中のページテーブルの分は残る
+    assert!(used_frames() - used_before <= 2);
+}